@@ -20,65 +20,600 @@ class LlmBindings {
           lookup)
       : _lookup = lookup;
 
-  ffi.Pointer<ffi.Char> load_model_ffi(
+  /// Sets the directory models are downloaded to and loaded from, e.g. the
+  /// app's documents directory on iOS/Android; call it before anything else.
+  /// Models go to `<root>/models/<org>/<name>` and the Hub's download cache to
+  /// `<root>/hub-cache`. Pass null to fall back to `$LLM_RUNNER_HOME`, or to
+  /// `models` in the working directory if that is unset.
+  int set_storage_root_c(
+    ffi.Pointer<ffi.Char> path,
+  ) {
+    return _set_storage_root_c(
+      path,
+    );
+  }
+
+  late final _set_storage_root_cPtr =
+      _lookup<ffi.NativeFunction<ffi.Int32 Function(ffi.Pointer<ffi.Char>)>>(
+          'set_storage_root_c');
+  late final _set_storage_root_c =
+      _set_storage_root_cPtr.asFunction<int Function(ffi.Pointer<ffi.Char>)>();
+
+  int download_model_c(
+    ffi.Pointer<ffi.Char> model_name,
+  ) {
+    return _download_model_c(
+      model_name,
+    );
+  }
+
+  late final _download_model_cPtr =
+      _lookup<ffi.NativeFunction<ffi.Int32 Function(ffi.Pointer<ffi.Char>)>>(
+          'download_model_c');
+  late final _download_model_c =
+      _download_model_cPtr.asFunction<int Function(ffi.Pointer<ffi.Char>)>();
+
+  int load_model_c(
     ffi.Pointer<ffi.Char> model_name,
   ) {
-    return _load_model_ffi(
+    return _load_model_c(
       model_name,
     );
   }
 
-  late final _load_model_ffiPtr = _lookup<
+  late final _load_model_cPtr =
+      _lookup<ffi.NativeFunction<ffi.Int32 Function(ffi.Pointer<ffi.Char>)>>(
+          'load_model_c');
+  late final _load_model_c =
+      _load_model_cPtr.asFunction<int Function(ffi.Pointer<ffi.Char>)>();
+
+  /// Downloads a quantized GGUF file, e.g. `TheBloke/TinyLlama-1.1B-Chat-v1.0-GGUF`
+  /// and `tinyllama-1.1b-chat-v1.0.Q4_K_M.gguf`. The tokenizer files come from
+  /// `tokenizer_repo`, or from `repo_id` when it is null.
+  int download_gguf_model_c(
+    ffi.Pointer<ffi.Char> repo_id,
+    ffi.Pointer<ffi.Char> filename,
+    ffi.Pointer<ffi.Char> tokenizer_repo,
+  ) {
+    return _download_gguf_model_c(
+      repo_id,
+      filename,
+      tokenizer_repo,
+    );
+  }
+
+  late final _download_gguf_model_cPtr = _lookup<
       ffi.NativeFunction<
-          ffi.Pointer<ffi.Char> Function(
-              ffi.Pointer<ffi.Char>)>>('load_model_ffi');
-  late final _load_model_ffi = _load_model_ffiPtr
-      .asFunction<ffi.Pointer<ffi.Char> Function(ffi.Pointer<ffi.Char>)>();
+          ffi.Int32 Function(ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Char>)>>('download_gguf_model_c');
+  late final _download_gguf_model_c = _download_gguf_model_cPtr.asFunction<
+      int Function(ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.Char>,
+          ffi.Pointer<ffi.Char>)>();
 
-  ffi.Pointer<ffi.Char> run_inference_c(
+  /// Like `load_model_c`, for a quantized GGUF file. Arguments are the same as
+  /// for `download_gguf_model_c`.
+  int load_gguf_model_c(
+    ffi.Pointer<ffi.Char> repo_id,
+    ffi.Pointer<ffi.Char> filename,
+    ffi.Pointer<ffi.Char> tokenizer_repo,
+  ) {
+    return _load_gguf_model_c(
+      repo_id,
+      filename,
+      tokenizer_repo,
+    );
+  }
+
+  late final _load_gguf_model_cPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Char>)>>('load_gguf_model_c');
+  late final _load_gguf_model_c = _load_gguf_model_cPtr.asFunction<
+      int Function(ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.Char>,
+          ffi.Pointer<ffi.Char>)>();
+
+  /// Drops the loaded model, freeing its weights, memory maps and KV cache,
+  /// e.g. when the app goes to the background. A running generation is
+  /// cancelled; its memory is released once that call returns. Chat sessions
+  /// stay valid and replay their history on the next load. Succeeds if no
+  /// model was loaded.
+  int unload_model_c() {
+    return _unload_model_c();
+  }
+
+  late final _unload_model_cPtr =
+      _lookup<ffi.NativeFunction<ffi.Int32 Function()>>('unload_model_c');
+  late final _unload_model_c = _unload_model_cPtr.asFunction<int Function()>();
+
+  /// Runs the prompt with the model's default settings and stores the
+  /// response in `*out` (free it with `free_string_c`). If the run was
+  /// cancelled the status is `Cancelled` and `*out` holds the partial response.
+  int run_inference_c(
     ffi.Pointer<ffi.Char> input,
+    ffi.Pointer<ffi.Pointer<ffi.Char>> out,
   ) {
     return _run_inference_c(
       input,
+      out,
     );
   }
 
   late final _run_inference_cPtr = _lookup<
       ffi.NativeFunction<
-          ffi.Pointer<ffi.Char> Function(
-              ffi.Pointer<ffi.Char>)>>('run_inference_c');
-  late final _run_inference_c = _run_inference_cPtr
-      .asFunction<ffi.Pointer<ffi.Char> Function(ffi.Pointer<ffi.Char>)>();
+          ffi.Int32 Function(ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Pointer<ffi.Char>>)>>('run_inference_c');
+  late final _run_inference_c = _run_inference_cPtr.asFunction<
+      int Function(
+          ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.Pointer<ffi.Char>>)>();
+
+  /// Like `run_inference_c`, but with sampling settings passed as a JSON object,
+  /// e.g. `{"temperature": 0.7, "top_k": 40, "top_p": 0.9, "min_p": 0.05, "seed": 42,
+  /// "repetition_penalty": 1.1, "frequency_penalty": 0.2, "presence_penalty": 0.1,
+  /// "penalty_last_n": 64, "max_new_tokens": 256, "stop": ["\nUser:"],
+  /// "context_overflow": "truncate_middle"}`. `context_overflow` is "error" (default),
+  /// "truncate_oldest" or "truncate_middle".
+  /// Missing fields fall back to the model's defaults (its `generation_config.json`,
+  /// then the built-in ones); a null `params_json` uses all defaults.
+  int run_inference_with_params_c(
+    ffi.Pointer<ffi.Char> input,
+    ffi.Pointer<ffi.Char> params_json,
+    ffi.Pointer<ffi.Pointer<ffi.Char>> out,
+  ) {
+    return _run_inference_with_params_c(
+      input,
+      params_json,
+      out,
+    );
+  }
+
+  late final _run_inference_with_params_cPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(
+              ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Char>,
+              ffi.Pointer<
+                  ffi.Pointer<ffi.Char>>)>>('run_inference_with_params_c');
+  late final _run_inference_with_params_c =
+      _run_inference_with_params_cPtr.asFunction<
+          int Function(ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Pointer<ffi.Char>>)>();
+
+  /// Streams the response through `callback`: one `Token` event per decoded
+  /// text fragment, then a single `Done` event carrying the full text and
+  /// stats, or an `Error` event with its status code. A cancelled run ends
+  /// with a `Done` event holding the partial text, `FinishReason::Cancelled`
+  /// and status `Cancelled`. The callback runs on the calling thread before
+  /// this function returns, which then returns the same status.
+  /// `params_json` is the same as for `run_inference_with_params_c` and may be null.
+  int run_inference_stream_c(
+    ffi.Pointer<ffi.Char> input,
+    ffi.Pointer<ffi.Char> params_json,
+    ffi.Pointer<
+        ffi.NativeFunction<
+            ffi.Void Function(ffi.Pointer<StreamEvent> event,
+                ffi.Pointer<ffi.Void> user_data)>> callback,
+    ffi.Pointer<ffi.Void> user_data,
+  ) {
+    return _run_inference_stream_c(
+      input,
+      params_json,
+      callback,
+      user_data,
+    );
+  }
+
+  late final _run_inference_stream_cPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(
+              ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Char>,
+              ffi.Pointer<
+                  ffi.NativeFunction<
+                      ffi.Void Function(ffi.Pointer<StreamEvent> event,
+                          ffi.Pointer<ffi.Void> user_data)>>,
+              ffi.Pointer<ffi.Void>)>>('run_inference_stream_c');
+  late final _run_inference_stream_c = _run_inference_stream_cPtr.asFunction<
+      int Function(
+          ffi.Pointer<ffi.Char>,
+          ffi.Pointer<ffi.Char>,
+          ffi.Pointer<
+              ffi.NativeFunction<
+                  ffi.Void Function(ffi.Pointer<StreamEvent> event,
+                      ffi.Pointer<ffi.Void> user_data)>>,
+          ffi.Pointer<ffi.Void>)>();
+
+  /// Clears the KV cache of the loaded model. Regular inference calls already
+  /// start from an empty cache; this frees the cached keys/values by hand.
+  int reset_cache_c() {
+    return _reset_cache_c();
+  }
+
+  late final _reset_cache_cPtr =
+      _lookup<ffi.NativeFunction<ffi.Int32 Function()>>('reset_cache_c');
+  late final _reset_cache_c = _reset_cache_cPtr.asFunction<int Function()>();
+
+  /// Stores a JSON description of the loaded model in `*out` (free it with
+  /// `free_string_c`): `{"name", "architecture", "context_length",
+  /// "cached_tokens", "weights_bytes", "kv_cache_bytes", "memory_bytes"}`.
+  /// `memory_bytes` estimates the resident memory of weights plus KV cache.
+  /// Does not wait for a running generation.
+  int model_info_c(
+    ffi.Pointer<ffi.Pointer<ffi.Char>> out,
+  ) {
+    return _model_info_c(
+      out,
+    );
+  }
+
+  late final _model_info_cPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(
+              ffi.Pointer<ffi.Pointer<ffi.Char>>)>>('model_info_c');
+  late final _model_info_c = _model_info_cPtr
+      .asFunction<int Function(ffi.Pointer<ffi.Pointer<ffi.Char>>)>();
 
-  ffi.Pointer<ffi.Char> download_model_ffi(
+  /// Overrides the chat template used for `model_name`, now and on future
+  /// loads. `template` is Jinja source in Hugging Face's `chat_template`
+  /// format; pass null to go back to the model's own template.
+  int set_chat_template_c(
     ffi.Pointer<ffi.Char> model_name,
+    ffi.Pointer<ffi.Char> template_,
   ) {
-    return _download_model_ffi(
+    return _set_chat_template_c(
       model_name,
+      template_,
     );
   }
 
-  late final _download_model_ffiPtr = _lookup<
+  late final _set_chat_template_cPtr = _lookup<
       ffi.NativeFunction<
-          ffi.Pointer<ffi.Char> Function(
-              ffi.Pointer<ffi.Char>)>>('download_model_ffi');
-  late final _download_model_ffi = _download_model_ffiPtr
-      .asFunction<ffi.Pointer<ffi.Char> Function(ffi.Pointer<ffi.Char>)>();
+          ffi.Int32 Function(ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Char>)>>('set_chat_template_c');
+  late final _set_chat_template_c = _set_chat_template_cPtr
+      .asFunction<int Function(ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.Char>)>();
+
+  /// Asks the running generation to stop. Safe to call from any thread; the
+  /// interrupted call returns (or streams as `Done`) the text generated so far.
+  /// Returns false if nothing was running.
+  bool cancel_inference_c() {
+    return _cancel_inference_c();
+  }
 
-  void free_string(
-    ffi.Pointer<ffi.Char> ptr,
+  late final _cancel_inference_cPtr =
+      _lookup<ffi.NativeFunction<ffi.Bool Function()>>('cancel_inference_c');
+  late final _cancel_inference_c =
+      _cancel_inference_cPtr.asFunction<bool Function()>();
+
+  /// Starts a new chat session. `system_prompt` may be null. The session must
+  /// be released with `chat_session_destroy_c`. Returns null on failure.
+  ffi.Pointer<ChatSession> chat_session_create_c(
+    ffi.Pointer<ffi.Char> system_prompt,
   ) {
-    return _free_string(
-      ptr,
+    return _chat_session_create_c(
+      system_prompt,
+    );
+  }
+
+  late final _chat_session_create_cPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Pointer<ChatSession> Function(
+              ffi.Pointer<ffi.Char>)>>('chat_session_create_c');
+  late final _chat_session_create_c = _chat_session_create_cPtr
+      .asFunction<ffi.Pointer<ChatSession> Function(ffi.Pointer<ffi.Char>)>();
+
+  /// Adds a message to the session. `role` is "system", "user" or "assistant".
+  int chat_session_append_c(
+    ffi.Pointer<ChatSession> session,
+    ffi.Pointer<ffi.Char> role,
+    ffi.Pointer<ffi.Char> content,
+  ) {
+    return _chat_session_append_c(
+      session,
+      role,
+      content,
+    );
+  }
+
+  late final _chat_session_append_cPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(ffi.Pointer<ChatSession>, ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Char>)>>('chat_session_append_c');
+  late final _chat_session_append_c = _chat_session_append_cPtr.asFunction<
+      int Function(ffi.Pointer<ChatSession>, ffi.Pointer<ffi.Char>,
+          ffi.Pointer<ffi.Char>)>();
+
+  /// Generates the assistant's reply to the session's pending messages, stores
+  /// it in `*out` (free it with `free_string_c`) and records it in the
+  /// conversation. Only the new turn is prefilled while the model's KV cache
+  /// still holds this session. `params_json` may be null.
+  int chat_session_generate_c(
+    ffi.Pointer<ChatSession> session,
+    ffi.Pointer<ffi.Char> params_json,
+    ffi.Pointer<ffi.Pointer<ffi.Char>> out,
+  ) {
+    return _chat_session_generate_c(
+      session,
+      params_json,
+      out,
     );
   }
 
-  late final _free_stringPtr =
+  late final _chat_session_generate_cPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(ffi.Pointer<ChatSession>, ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Pointer<ffi.Char>>)>>('chat_session_generate_c');
+  late final _chat_session_generate_c = _chat_session_generate_cPtr.asFunction<
+      int Function(ffi.Pointer<ChatSession>, ffi.Pointer<ffi.Char>,
+          ffi.Pointer<ffi.Pointer<ffi.Char>>)>();
+
+  void chat_session_destroy_c(
+    ffi.Pointer<ChatSession> session,
+  ) {
+    return _chat_session_destroy_c(
+      session,
+    );
+  }
+
+  late final _chat_session_destroy_cPtr =
+      _lookup<ffi.NativeFunction<ffi.Void Function(ffi.Pointer<ChatSession>)>>(
+          'chat_session_destroy_c');
+  late final _chat_session_destroy_c = _chat_session_destroy_cPtr
+      .asFunction<void Function(ffi.Pointer<ChatSession>)>();
+
+  /// Loads a model from the Hugging Face Hub, like `load_model_c`, but
+  /// returns it as a handle instead of replacing the global model. Any number
+  /// of models can be loaded this way; release each with `llm_model_free`.
+  /// Returns null on failure.
+  ffi.Pointer<ModelHandle> llm_model_load(
+    ffi.Pointer<ffi.Char> model_name,
+  ) {
+    return _llm_model_load(
+      model_name,
+    );
+  }
+
+  late final _llm_model_loadPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Pointer<ModelHandle> Function(
+              ffi.Pointer<ffi.Char>)>>('llm_model_load');
+  late final _llm_model_load = _llm_model_loadPtr
+      .asFunction<ffi.Pointer<ModelHandle> Function(ffi.Pointer<ffi.Char>)>();
+
+  /// Like `llm_model_load`, for a quantized GGUF file. Arguments are the same
+  /// as for `download_gguf_model_c`.
+  ffi.Pointer<ModelHandle> llm_model_load_gguf(
+    ffi.Pointer<ffi.Char> repo_id,
+    ffi.Pointer<ffi.Char> filename,
+    ffi.Pointer<ffi.Char> tokenizer_repo,
+  ) {
+    return _llm_model_load_gguf(
+      repo_id,
+      filename,
+      tokenizer_repo,
+    );
+  }
+
+  late final _llm_model_load_ggufPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Pointer<ModelHandle> Function(ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Char>)>>('llm_model_load_gguf');
+  late final _llm_model_load_gguf = _llm_model_load_ggufPtr.asFunction<
+      ffi.Pointer<ModelHandle> Function(ffi.Pointer<ffi.Char>,
+          ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.Char>)>();
+
+  /// Releases a handle from `llm_model_load`. No call on it may be running or
+  /// start afterwards. Null is ignored.
+  void llm_model_free(
+    ffi.Pointer<ModelHandle> model,
+  ) {
+    return _llm_model_free(
+      model,
+    );
+  }
+
+  late final _llm_model_freePtr =
+      _lookup<ffi.NativeFunction<ffi.Void Function(ffi.Pointer<ModelHandle>)>>(
+          'llm_model_free');
+  late final _llm_model_free =
+      _llm_model_freePtr.asFunction<void Function(ffi.Pointer<ModelHandle>)>();
+
+  /// `run_inference_with_params_c` on the given model.
+  int llm_model_run_inference(
+    ffi.Pointer<ModelHandle> model,
+    ffi.Pointer<ffi.Char> input,
+    ffi.Pointer<ffi.Char> params_json,
+    ffi.Pointer<ffi.Pointer<ffi.Char>> out,
+  ) {
+    return _llm_model_run_inference(
+      model,
+      input,
+      params_json,
+      out,
+    );
+  }
+
+  late final _llm_model_run_inferencePtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(ffi.Pointer<ModelHandle>, ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Pointer<ffi.Char>>)>>('llm_model_run_inference');
+  late final _llm_model_run_inference = _llm_model_run_inferencePtr.asFunction<
+      int Function(ffi.Pointer<ModelHandle>, ffi.Pointer<ffi.Char>,
+          ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.Pointer<ffi.Char>>)>();
+
+  /// `run_inference_stream_c` on the given model.
+  int llm_model_run_inference_stream(
+    ffi.Pointer<ModelHandle> model,
+    ffi.Pointer<ffi.Char> input,
+    ffi.Pointer<ffi.Char> params_json,
+    ffi.Pointer<
+        ffi.NativeFunction<
+            ffi.Void Function(ffi.Pointer<StreamEvent> event,
+                ffi.Pointer<ffi.Void> user_data)>> callback,
+    ffi.Pointer<ffi.Void> user_data,
+  ) {
+    return _llm_model_run_inference_stream(
+      model,
+      input,
+      params_json,
+      callback,
+      user_data,
+    );
+  }
+
+  late final _llm_model_run_inference_streamPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(
+              ffi.Pointer<ModelHandle>,
+              ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Char>,
+              ffi.Pointer<
+                  ffi.NativeFunction<
+                      ffi.Void Function(ffi.Pointer<StreamEvent> event,
+                          ffi.Pointer<ffi.Void> user_data)>>,
+              ffi.Pointer<ffi.Void>)>>('llm_model_run_inference_stream');
+  late final _llm_model_run_inference_stream =
+      _llm_model_run_inference_streamPtr.asFunction<
+          int Function(
+              ffi.Pointer<ModelHandle>,
+              ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Char>,
+              ffi.Pointer<
+                  ffi.NativeFunction<
+                      ffi.Void Function(ffi.Pointer<StreamEvent> event,
+                          ffi.Pointer<ffi.Void> user_data)>>,
+              ffi.Pointer<ffi.Void>)>();
+
+  /// `reset_cache_c` on the given model.
+  int llm_model_reset_cache(
+    ffi.Pointer<ModelHandle> model,
+  ) {
+    return _llm_model_reset_cache(
+      model,
+    );
+  }
+
+  late final _llm_model_reset_cachePtr =
+      _lookup<ffi.NativeFunction<ffi.Int32 Function(ffi.Pointer<ModelHandle>)>>(
+          'llm_model_reset_cache');
+  late final _llm_model_reset_cache = _llm_model_reset_cachePtr
+      .asFunction<int Function(ffi.Pointer<ModelHandle>)>();
+
+  /// `model_info_c` for the given model.
+  int llm_model_info(
+    ffi.Pointer<ModelHandle> model,
+    ffi.Pointer<ffi.Pointer<ffi.Char>> out,
+  ) {
+    return _llm_model_info(
+      model,
+      out,
+    );
+  }
+
+  late final _llm_model_infoPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(ffi.Pointer<ModelHandle>,
+              ffi.Pointer<ffi.Pointer<ffi.Char>>)>>('llm_model_info');
+  late final _llm_model_info = _llm_model_infoPtr.asFunction<
+      int Function(
+          ffi.Pointer<ModelHandle>, ffi.Pointer<ffi.Pointer<ffi.Char>>)>();
+
+  /// Replaces the chat template of this model only; pass null to go back to
+  /// the model's own template. Use `set_chat_template_c` to also apply it to
+  /// future loads.
+  int llm_model_set_chat_template(
+    ffi.Pointer<ModelHandle> model,
+    ffi.Pointer<ffi.Char> template_,
+  ) {
+    return _llm_model_set_chat_template(
+      model,
+      template_,
+    );
+  }
+
+  late final _llm_model_set_chat_templatePtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(ffi.Pointer<ModelHandle>,
+              ffi.Pointer<ffi.Char>)>>('llm_model_set_chat_template');
+  late final _llm_model_set_chat_template =
+      _llm_model_set_chat_templatePtr.asFunction<
+          int Function(ffi.Pointer<ModelHandle>, ffi.Pointer<ffi.Char>)>();
+
+  /// `cancel_inference_c` for the given model.
+  bool llm_model_cancel(
+    ffi.Pointer<ModelHandle> model,
+  ) {
+    return _llm_model_cancel(
+      model,
+    );
+  }
+
+  late final _llm_model_cancelPtr =
+      _lookup<ffi.NativeFunction<ffi.Bool Function(ffi.Pointer<ModelHandle>)>>(
+          'llm_model_cancel');
+  late final _llm_model_cancel = _llm_model_cancelPtr
+      .asFunction<bool Function(ffi.Pointer<ModelHandle>)>();
+
+  /// `chat_session_generate_c` on the given model. A session may move between
+  /// models; its history is replayed on the first turn with a new one.
+  int llm_model_chat_session_generate(
+    ffi.Pointer<ModelHandle> model,
+    ffi.Pointer<ChatSession> session,
+    ffi.Pointer<ffi.Char> params_json,
+    ffi.Pointer<ffi.Pointer<ffi.Char>> out,
+  ) {
+    return _llm_model_chat_session_generate(
+      model,
+      session,
+      params_json,
+      out,
+    );
+  }
+
+  late final _llm_model_chat_session_generatePtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(
+              ffi.Pointer<ModelHandle>,
+              ffi.Pointer<ChatSession>,
+              ffi.Pointer<ffi.Char>,
+              ffi.Pointer<
+                  ffi.Pointer<ffi.Char>>)>>('llm_model_chat_session_generate');
+  late final _llm_model_chat_session_generate =
+      _llm_model_chat_session_generatePtr.asFunction<
+          int Function(ffi.Pointer<ModelHandle>, ffi.Pointer<ChatSession>,
+              ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.Pointer<ffi.Char>>)>();
+
+  /// Message describing the last failed call on this thread, or null if it
+  /// succeeded. The string is owned by the library and stays valid until the
+  /// next call on the same thread; do not free it.
+  ffi.Pointer<ffi.Char> last_error_c() {
+    return _last_error_c();
+  }
+
+  late final _last_error_cPtr =
+      _lookup<ffi.NativeFunction<ffi.Pointer<ffi.Char> Function()>>(
+          'last_error_c');
+  late final _last_error_c =
+      _last_error_cPtr.asFunction<ffi.Pointer<ffi.Char> Function()>();
+
+  void free_string_c(
+    ffi.Pointer<ffi.Char> s,
+  ) {
+    return _free_string_c(
+      s,
+    );
+  }
+
+  late final _free_string_cPtr =
       _lookup<ffi.NativeFunction<ffi.Void Function(ffi.Pointer<ffi.Char>)>>(
-          'free_string');
-  late final _free_string =
-      _free_stringPtr.asFunction<void Function(ffi.Pointer<ffi.Char>)>();
+          'free_string_c');
+  late final _free_string_c =
+      _free_string_cPtr.asFunction<void Function(ffi.Pointer<ffi.Char>)>();
 
+  /// Tokenizes `text` with the loaded model's tokenizer, special tokens
+  /// included, and stores the number of ids in `*length`. Free the array with
+  /// `free_array`. Returns null on failure.
   ffi.Pointer<ffi.Uint32> tokenize_text_c(
     ffi.Pointer<ffi.Char> text,
     ffi.Pointer<ffi.UintPtr> length,
@@ -97,52 +632,379 @@ class LlmBindings {
       ffi.Pointer<ffi.Uint32> Function(
           ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.UintPtr>)>();
 
-  void free_array(
-    ffi.Pointer<ffi.Uint32> ptr,
+  /// Turns `length` token ids back into text with the loaded model's
+  /// tokenizer, leaving out special tokens. Free the result with
+  /// `free_string_c`. Returns null on failure.
+  ffi.Pointer<ffi.Char> detokenize_ids(
+    ffi.Pointer<ffi.Uint32> tokens,
     int length,
   ) {
-    return _free_array(
-      ptr,
+    return _detokenize_ids(
+      tokens,
       length,
     );
   }
 
-  late final _free_arrayPtr = _lookup<
+  late final _detokenize_idsPtr = _lookup<
       ffi.NativeFunction<
-          ffi.Void Function(
-              ffi.Pointer<ffi.Uint32>, ffi.UintPtr)>>('free_array');
-  late final _free_array =
-      _free_arrayPtr.asFunction<void Function(ffi.Pointer<ffi.Uint32>, int)>();
+          ffi.Pointer<ffi.Char> Function(
+              ffi.Pointer<ffi.Uint32>, ffi.UintPtr)>>('detokenize_ids');
+  late final _detokenize_ids = _detokenize_idsPtr.asFunction<
+      ffi.Pointer<ffi.Char> Function(ffi.Pointer<ffi.Uint32>, int)>();
 
-  ffi.Pointer<ffi.Void> tokenize_text(
-    ffi.Pointer<ffi.Char> _text,
+  /// Stores the number of tokens `text` takes up with the loaded model's
+  /// tokenizer, special tokens included, in `*count`.
+  int count_tokens_c(
+    ffi.Pointer<ffi.Char> text,
+    ffi.Pointer<ffi.UintPtr> count,
   ) {
-    return _tokenize_text(
-      _text,
+    return _count_tokens_c(
+      text,
+      count,
     );
   }
 
-  late final _tokenize_textPtr = _lookup<
+  late final _count_tokens_cPtr = _lookup<
       ffi.NativeFunction<
-          ffi.Pointer<ffi.Void> Function(
-              ffi.Pointer<ffi.Char>)>>('tokenize_text');
-  late final _tokenize_text = _tokenize_textPtr
-      .asFunction<ffi.Pointer<ffi.Void> Function(ffi.Pointer<ffi.Char>)>();
+          ffi.Int32 Function(ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.UintPtr>)>>('count_tokens_c');
+  late final _count_tokens_c = _count_tokens_cPtr.asFunction<
+      int Function(ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.UintPtr>)>();
 
-  ffi.Pointer<ffi.Char> detokenize_ids(
-    ffi.Pointer<ffi.Uint32> _tokens,
-    int _length,
+  /// `tokenize_text_c` with the given model's tokenizer.
+  ffi.Pointer<ffi.Uint32> llm_model_tokenize(
+    ffi.Pointer<ModelHandle> model,
+    ffi.Pointer<ffi.Char> text,
+    ffi.Pointer<ffi.UintPtr> length,
   ) {
-    return _detokenize_ids(
-      _tokens,
-      _length,
+    return _llm_model_tokenize(
+      model,
+      text,
+      length,
     );
   }
 
-  late final _detokenize_idsPtr = _lookup<
+  late final _llm_model_tokenizePtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Pointer<ffi.Uint32> Function(ffi.Pointer<ModelHandle>,
+              ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.UintPtr>)>>('llm_model_tokenize');
+  late final _llm_model_tokenize = _llm_model_tokenizePtr.asFunction<
+      ffi.Pointer<ffi.Uint32> Function(ffi.Pointer<ModelHandle>,
+          ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.UintPtr>)>();
+
+  /// `detokenize_ids` with the given model's tokenizer.
+  ffi.Pointer<ffi.Char> llm_model_detokenize(
+    ffi.Pointer<ModelHandle> model,
+    ffi.Pointer<ffi.Uint32> tokens,
+    int length,
+  ) {
+    return _llm_model_detokenize(
+      model,
+      tokens,
+      length,
+    );
+  }
+
+  late final _llm_model_detokenizePtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Pointer<ffi.Char> Function(ffi.Pointer<ModelHandle>,
+              ffi.Pointer<ffi.Uint32>, ffi.UintPtr)>>('llm_model_detokenize');
+  late final _llm_model_detokenize = _llm_model_detokenizePtr.asFunction<
+      ffi.Pointer<ffi.Char> Function(
+          ffi.Pointer<ModelHandle>, ffi.Pointer<ffi.Uint32>, int)>();
+
+  /// `count_tokens_c` with the given model's tokenizer.
+  int llm_model_count_tokens(
+    ffi.Pointer<ModelHandle> model,
+    ffi.Pointer<ffi.Char> text,
+    ffi.Pointer<ffi.UintPtr> count,
+  ) {
+    return _llm_model_count_tokens(
+      model,
+      text,
+      count,
+    );
+  }
+
+  late final _llm_model_count_tokensPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(ffi.Pointer<ModelHandle>, ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.UintPtr>)>>('llm_model_count_tokens');
+  late final _llm_model_count_tokens = _llm_model_count_tokensPtr.asFunction<
+      int Function(ffi.Pointer<ModelHandle>, ffi.Pointer<ffi.Char>,
+          ffi.Pointer<ffi.UintPtr>)>();
+
+  /// Tokenizes `text` with the loaded model's tokenizer and stores a JSON
+  /// array in `*out` (free it with `free_string_c`), one object per token:
+  /// `{"id", "piece", "byte_start", "byte_end", "char_start", "char_end",
+  /// "special"}`. Ranges are half-open and point into `text`; `char_*` count
+  /// Unicode scalar values. Special tokens added around the text, such as
+  /// BOS, are included when `add_special_tokens` is set and have an empty
+  /// range at 0.
+  int tokenize_with_offsets_c(
+    ffi.Pointer<ffi.Char> text,
+    bool add_special_tokens,
+    ffi.Pointer<ffi.Pointer<ffi.Char>> out,
+  ) {
+    return _tokenize_with_offsets_c(
+      text,
+      add_special_tokens,
+      out,
+    );
+  }
+
+  late final _tokenize_with_offsets_cPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(ffi.Pointer<ffi.Char>, ffi.Bool,
+              ffi.Pointer<ffi.Pointer<ffi.Char>>)>>('tokenize_with_offsets_c');
+  late final _tokenize_with_offsets_c = _tokenize_with_offsets_cPtr.asFunction<
+      int Function(
+          ffi.Pointer<ffi.Char>, bool, ffi.Pointer<ffi.Pointer<ffi.Char>>)>();
+
+  /// `tokenize_with_offsets_c` with the given model's tokenizer.
+  int llm_model_tokenize_with_offsets(
+    ffi.Pointer<ModelHandle> model,
+    ffi.Pointer<ffi.Char> text,
+    bool add_special_tokens,
+    ffi.Pointer<ffi.Pointer<ffi.Char>> out,
+  ) {
+    return _llm_model_tokenize_with_offsets(
+      model,
+      text,
+      add_special_tokens,
+      out,
+    );
+  }
+
+  late final _llm_model_tokenize_with_offsetsPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(
+              ffi.Pointer<ModelHandle>,
+              ffi.Pointer<ffi.Char>,
+              ffi.Bool,
+              ffi.Pointer<
+                  ffi.Pointer<ffi.Char>>)>>('llm_model_tokenize_with_offsets');
+  late final _llm_model_tokenize_with_offsets =
+      _llm_model_tokenize_with_offsetsPtr.asFunction<
+          int Function(ffi.Pointer<ModelHandle>, ffi.Pointer<ffi.Char>, bool,
+              ffi.Pointer<ffi.Pointer<ffi.Char>>)>();
+
+  /// Stores a JSON description of the loaded model's vocabulary in `*out`
+  /// (free it with `free_string_c`): `{"vocab_size", "bos_token_id",
+  /// "eos_token_id", "pad_token_id", "unk_token_id", "added_tokens"}`. The
+  /// token ids are null when the model has no such token; `added_tokens` is
+  /// a list of `{"id", "content", "special"}` sorted by id.
+  int vocab_info_c(
+    ffi.Pointer<ffi.Pointer<ffi.Char>> out,
+  ) {
+    return _vocab_info_c(
+      out,
+    );
+  }
+
+  late final _vocab_info_cPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(
+              ffi.Pointer<ffi.Pointer<ffi.Char>>)>>('vocab_info_c');
+  late final _vocab_info_c = _vocab_info_cPtr
+      .asFunction<int Function(ffi.Pointer<ffi.Pointer<ffi.Char>>)>();
+
+  /// Stores the id of `token` in `*id`. `token` is the vocabulary entry, e.g.
+  /// `▁hello` or `<|im_end|>`, not text to tokenize.
+  int token_to_id_c(
+    ffi.Pointer<ffi.Char> token,
+    ffi.Pointer<ffi.Uint32> id,
+  ) {
+    return _token_to_id_c(
+      token,
+      id,
+    );
+  }
+
+  late final _token_to_id_cPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Uint32>)>>('token_to_id_c');
+  late final _token_to_id_c = _token_to_id_cPtr.asFunction<
+      int Function(ffi.Pointer<ffi.Char>, ffi.Pointer<ffi.Uint32>)>();
+
+  /// The vocabulary entry for `id`. Free it with `free_string_c`. Returns null
+  /// on failure.
+  ffi.Pointer<ffi.Char> id_to_token_c(
+    int id,
+  ) {
+    return _id_to_token_c(
+      id,
+    );
+  }
+
+  late final _id_to_token_cPtr =
+      _lookup<ffi.NativeFunction<ffi.Pointer<ffi.Char> Function(ffi.Uint32)>>(
+          'id_to_token_c');
+  late final _id_to_token_c =
+      _id_to_token_cPtr.asFunction<ffi.Pointer<ffi.Char> Function(int)>();
+
+  /// `vocab_info_c` for the given model.
+  int llm_model_vocab_info(
+    ffi.Pointer<ModelHandle> model,
+    ffi.Pointer<ffi.Pointer<ffi.Char>> out,
+  ) {
+    return _llm_model_vocab_info(
+      model,
+      out,
+    );
+  }
+
+  late final _llm_model_vocab_infoPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(ffi.Pointer<ModelHandle>,
+              ffi.Pointer<ffi.Pointer<ffi.Char>>)>>('llm_model_vocab_info');
+  late final _llm_model_vocab_info = _llm_model_vocab_infoPtr.asFunction<
+      int Function(
+          ffi.Pointer<ModelHandle>, ffi.Pointer<ffi.Pointer<ffi.Char>>)>();
+
+  /// `token_to_id_c` with the given model's tokenizer.
+  int llm_model_token_to_id(
+    ffi.Pointer<ModelHandle> model,
+    ffi.Pointer<ffi.Char> token,
+    ffi.Pointer<ffi.Uint32> id,
+  ) {
+    return _llm_model_token_to_id(
+      model,
+      token,
+      id,
+    );
+  }
+
+  late final _llm_model_token_to_idPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Int32 Function(ffi.Pointer<ModelHandle>, ffi.Pointer<ffi.Char>,
+              ffi.Pointer<ffi.Uint32>)>>('llm_model_token_to_id');
+  late final _llm_model_token_to_id = _llm_model_token_to_idPtr.asFunction<
+      int Function(ffi.Pointer<ModelHandle>, ffi.Pointer<ffi.Char>,
+          ffi.Pointer<ffi.Uint32>)>();
+
+  /// `id_to_token_c` with the given model's tokenizer.
+  ffi.Pointer<ffi.Char> llm_model_id_to_token(
+    ffi.Pointer<ModelHandle> model,
+    int id,
+  ) {
+    return _llm_model_id_to_token(
+      model,
+      id,
+    );
+  }
+
+  late final _llm_model_id_to_tokenPtr = _lookup<
       ffi.NativeFunction<
           ffi.Pointer<ffi.Char> Function(
-              ffi.Pointer<ffi.Uint32>, ffi.UintPtr)>>('detokenize_ids');
-  late final _detokenize_ids = _detokenize_idsPtr.asFunction<
-      ffi.Pointer<ffi.Char> Function(ffi.Pointer<ffi.Uint32>, int)>();
+              ffi.Pointer<ModelHandle>, ffi.Uint32)>>('llm_model_id_to_token');
+  late final _llm_model_id_to_token = _llm_model_id_to_tokenPtr.asFunction<
+      ffi.Pointer<ffi.Char> Function(ffi.Pointer<ModelHandle>, int)>();
+
+  void free_array(
+    ffi.Pointer<ffi.Uint32> ptr,
+    int length,
+  ) {
+    return _free_array(
+      ptr,
+      length,
+    );
+  }
+
+  late final _free_arrayPtr = _lookup<
+      ffi.NativeFunction<
+          ffi.Void Function(
+              ffi.Pointer<ffi.Uint32>, ffi.UintPtr)>>('free_array');
+  late final _free_array =
+      _free_arrayPtr.asFunction<void Function(ffi.Pointer<ffi.Uint32>, int)>();
+}
+
+/// Why a generation run ended.
+abstract class FinishReason {
+  /// The model produced a stop token.
+  static const int FinishReason_Stop = 0;
+  /// The token limit was reached.
+  static const int FinishReason_Length = 1;
+  /// The run was cancelled; the output is partial.
+  static const int FinishReason_Cancelled = 2;
+}
+
+/// Status code returned by the C API. The values are stable; new codes are
+/// only ever appended.
+abstract class LlmStatus {
+  static const int LlmStatus_Ok = 0;
+  static const int LlmStatus_InvalidArgument = 1;
+  static const int LlmStatus_NotLoaded = 2;
+  static const int LlmStatus_Download = 3;
+  static const int LlmStatus_Auth = 4;
+  static const int LlmStatus_Io = 5;
+  static const int LlmStatus_Config = 6;
+  static const int LlmStatus_Load = 7;
+  static const int LlmStatus_Tokenizer = 8;
+  static const int LlmStatus_Template = 9;
+  static const int LlmStatus_Inference = 10;
+  static const int LlmStatus_ContextOverflow = 11;
+  static const int LlmStatus_Cancelled = 12;
+  static const int LlmStatus_Panic = 13;
+  static const int LlmStatus_InvalidModelId = 14;
+}
+
+abstract class StreamEventKind {
+  /// `text` holds the next fragment of the response.
+  static const int StreamEventKind_Token = 0;
+  /// Generation finished; `text` holds the full response (partial if
+  /// cancelled) and `stats` is filled in.
+  static const int StreamEventKind_Done = 1;
+  /// Generation failed; `text` holds the error message.
+  static const int StreamEventKind_Error = 2;
+}
+
+/// A conversation with the loaded model.
+///
+/// The session remembers every token it has fed to the model. As long as the
+/// model's KV cache still belongs to this session, a new turn only prefills
+/// the tokens added since the last reply; otherwise the whole history is
+/// replayed once. Models that can only append one token per pass (Phi, GGUF
+/// and Llamas with rope scaling or tied embeddings) also replay the history
+/// when that is cheaper than a pass per new token.
+final class ChatSession extends ffi.Opaque {}
+
+/// A loaded model as seen by the C API. Several can be alive at once, e.g.
+/// an embedding model next to a chat model; each has its own lock, KV cache
+/// and cancellation token.
+final class ModelHandle extends ffi.Opaque {}
+
+/// Timing and token counts of one generation run.
+final class GenerationStats extends ffi.Struct {
+  @ffi.Uint32()
+  external int prompt_tokens;
+
+  @ffi.Uint32()
+  external int generated_tokens;
+
+  @ffi.Uint64()
+  external int elapsed_ms;
+
+  @ffi.Double()
+  external double tokens_per_second;
+
+  @ffi.Int32()
+  external int finish_reason;
+}
+
+/// Event passed to a `StreamCallback`. `text` is only valid for the duration
+/// of the callback and must be copied if it is needed afterwards.
+final class StreamEvent extends ffi.Struct {
+  @ffi.Int32()
+  external int kind;
+
+  /// `Ok` except for `Error` events and the `Done` event of a cancelled run.
+  @ffi.Int32()
+  external int status;
+
+  external ffi.Pointer<ffi.Char> text;
+
+  external GenerationStats stats;
 }
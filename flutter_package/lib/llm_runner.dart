@@ -2,7 +2,7 @@ import 'dart:ffi';
 import 'dart:io';
 import 'package:ffi/ffi.dart';
 import 'package:path/path.dart' as path;
-import 'src/bindings.dart';
+import 'generated_bindings.dart';
 import 'src/models_config.dart';  // Import everything from models_config
 
 class LlmRunner {
@@ -11,11 +11,7 @@ class LlmRunner {
   static bool _isModelLoaded = false;
   static bool _isInitialized = false;
 
-  static late final DynamicLibrary _lib;
-  static late final LoadModel _loadModelFn;
-  static late final RunInference _runInferenceFn;
-  static late final FreeString _freeStringFn;
-  static late final DownloadModel _downloadModelFn;
+  static late final LlmBindings _bindings;
 
   static Future<String> generateText({
     required ModelConfig model,
@@ -39,14 +35,7 @@ class LlmRunner {
     
     try {
       print('Running inference...');
-      final input = prompt.toNativeUtf8();
-      final resultPtr = _runInferenceFn(input);
-      final response = resultPtr.toDartString();
-      
-      calloc.free(input);
-      _freeStringFn(resultPtr);
-      
-      return response;
+      return _runInference(prompt);
     } catch (e) {
       print('Error during inference: $e');
       throw Exception('Failed to generate response: $e');
@@ -64,36 +53,24 @@ class LlmRunner {
     
     await _initializeIfNeeded();
     
+    final modelNamePtr = model.name.toNativeUtf8().cast<Char>();
     try {
-      final modelNamePtr = model.name.toNativeUtf8();
-      final downloadPtr = _downloadModelFn(modelNamePtr);
-      final downloadResult = downloadPtr.toDartString();
-      print('Download result: $downloadResult');
-      _freeStringFn(downloadPtr);
-
-      final loadResultPtr = _loadModelFn(modelNamePtr);
-      final loadResult = loadResultPtr.toDartString();
-      print('Load result: $loadResult');
-      
-      calloc.free(modelNamePtr);
-      _freeStringFn(loadResultPtr);
-      
+      _check(_bindings.download_model_c(modelNamePtr), 'Download of ${model.name}');
+      print('Download complete');
+      _check(_bindings.load_model_c(modelNamePtr), 'Load of ${model.name}');
       print('Model switch complete');
     } catch (e) {
       print('Error during model switch: $e');
       rethrow;
+    } finally {
+      calloc.free(modelNamePtr);
     }
   }
 
   static Future<void> _initializeIfNeeded() async {
     if (_isInitialized) return;
 
-    _lib = DynamicLibrary.open(_getLibraryPath());
-    
-    _loadModelFn = _lib.lookupFunction<LoadModelC, LoadModel>('load_model_c');
-    _runInferenceFn = _lib.lookupFunction<RunInferenceC, RunInference>('run_inference_c');
-    _freeStringFn = _lib.lookupFunction<FreeStringC, FreeString>('free_string_c');
-    _downloadModelFn = _lib.lookupFunction<DownloadModelC, DownloadModel>('download_model_c');
+    _bindings = LlmBindings(DynamicLibrary.open(_getLibraryPath()));
 
     _isInitialized = true;
   }
@@ -154,36 +131,57 @@ Current directory: ${currentDir.path}
   /// Downloads a model if not already present
   static Future<void> downloadModel(ModelConfig model) async {
     await _initializeIfNeeded();
-    final modelNamePtr = model.name.toNativeUtf8();
-    final resultPtr = _downloadModelFn(modelNamePtr);
-    final result = resultPtr.toDartString();
-    print('Download result for ${model.name}: $result');
-    
-    calloc.free(modelNamePtr);
-    _freeStringFn(resultPtr);
+    final modelNamePtr = model.name.toNativeUtf8().cast<Char>();
+    try {
+      _check(_bindings.download_model_c(modelNamePtr), 'Download of ${model.name}');
+      print('Downloaded ${model.name}');
+    } finally {
+      calloc.free(modelNamePtr);
+    }
   }
 
   /// Pre-loads a model into memory
   static Future<void> loadModel(ModelConfig model) async {
     await _initializeIfNeeded();
-    final modelNamePtr = model.name.toNativeUtf8();
-    final resultPtr = _loadModelFn(modelNamePtr);
-    final result = resultPtr.toDartString();
-    print('Load result for ${model.name}: $result');
-    
-    calloc.free(modelNamePtr);
-    _freeStringFn(resultPtr);
+    final modelNamePtr = model.name.toNativeUtf8().cast<Char>();
+    try {
+      _check(_bindings.load_model_c(modelNamePtr), 'Load of ${model.name}');
+      print('Loaded ${model.name}');
+    } finally {
+      calloc.free(modelNamePtr);
+    }
   }
 
   /// Runs inference on the loaded model
   static Future<String> runInference(String prompt) async {
-    final promptPtr = prompt.toNativeUtf8();
-    final resultPtr = _runInferenceFn(promptPtr);
-    final response = resultPtr.toDartString();
-    
-    calloc.free(promptPtr);
-    _freeStringFn(resultPtr);
-    
-    return response;
+    await _initializeIfNeeded();
+    return _runInference(prompt);
+  }
+
+  static String _runInference(String prompt) {
+    final promptPtr = prompt.toNativeUtf8().cast<Char>();
+    final outPtr = calloc<Pointer<Char>>();
+    try {
+      final status = _bindings.run_inference_c(promptPtr, outPtr);
+      final resultPtr = outPtr.value;
+      final response = resultPtr == nullptr ? '' : resultPtr.cast<Utf8>().toDartString();
+      if (resultPtr != nullptr) {
+        _bindings.free_string_c(resultPtr);
+      }
+      _check(status, 'Inference');
+      return response;
+    } finally {
+      calloc.free(promptPtr);
+      calloc.free(outPtr);
+    }
+  }
+
+  /// Throws with the library's error message unless [status] is `LlmStatus_Ok`.
+  static void _check(int status, String action) {
+    if (status == LlmStatus.LlmStatus_Ok) return;
+    // Owned by the library, not freed here
+    final errorPtr = _bindings.last_error_c();
+    final message = errorPtr == nullptr ? 'status $status' : errorPtr.cast<Utf8>().toDartString();
+    throw Exception('$action failed: $message');
   }
 }
@@ -4,7 +4,9 @@ use std::path::PathBuf;
 fn main() {
     let crate_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
     
-    let config = cbindgen::Config::default();
+    let mut config = cbindgen::Config::default();
+    // Keep enum variants like `Error` from clashing in the C namespace
+    config.enumeration.prefix_with_name = true;
     
     let output_file = PathBuf::from(&crate_dir)
         .join("include")
@@ -12,13 +14,19 @@ fn main() {
 
     std::fs::create_dir_all(output_file.parent().unwrap()).unwrap();
     
-    cbindgen::Builder::new()
-        .with_crate(crate_dir)
-        .with_config(config)
-        .with_language(cbindgen::Language::C)
-        .with_header("/* Generated by cbindgen */")
-        .with_include_guard("LLM_RUNNER_H")
-        .generate()
-        .expect("Unable to generate bindings")
-        .write_to_file(output_file);
+    // src/bridge_generated.h carries the same declarations under its own guard
+    for (path, include_guard) in [
+        (output_file, "LLM_RUNNER_H"),
+        (PathBuf::from(&crate_dir).join("src").join("bridge_generated.h"), "BRIDGE_GENERATED_H"),
+    ] {
+        cbindgen::Builder::new()
+            .with_crate(&crate_dir)
+            .with_config(config.clone())
+            .with_language(cbindgen::Language::C)
+            .with_header("/* Generated by cbindgen */")
+            .with_include_guard(include_guard)
+            .generate()
+            .expect("Unable to generate bindings")
+            .write_to_file(path);
+    }
 }
@@ -8,20 +8,405 @@
 #include <stdint.h>
 #include <stdlib.h>
 
-char *download_model_c(const char *model_name);
+/**
+ * Why a generation run ended.
+ */
+typedef enum FinishReason {
+  /**
+   * The model produced a stop token.
+   */
+  FinishReason_Stop = 0,
+  /**
+   * The token limit was reached.
+   */
+  FinishReason_Length = 1,
+  /**
+   * The run was cancelled; the output is partial.
+   */
+  FinishReason_Cancelled = 2,
+} FinishReason;
 
-char *load_model_c(const char *model_name);
+/**
+ * Status code returned by the C API. The values are stable; new codes are
+ * only ever appended.
+ */
+typedef enum LlmStatus {
+  LlmStatus_Ok = 0,
+  LlmStatus_InvalidArgument = 1,
+  LlmStatus_NotLoaded = 2,
+  LlmStatus_Download = 3,
+  LlmStatus_Auth = 4,
+  LlmStatus_Io = 5,
+  LlmStatus_Config = 6,
+  LlmStatus_Load = 7,
+  LlmStatus_Tokenizer = 8,
+  LlmStatus_Template = 9,
+  LlmStatus_Inference = 10,
+  LlmStatus_ContextOverflow = 11,
+  LlmStatus_Cancelled = 12,
+  LlmStatus_Panic = 13,
+  LlmStatus_InvalidModelId = 14,
+} LlmStatus;
 
-char *run_inference_c(const char *input);
+typedef enum StreamEventKind {
+  /**
+   * `text` holds the next fragment of the response.
+   */
+  StreamEventKind_Token = 0,
+  /**
+   * Generation finished; `text` holds the full response (partial if
+   * cancelled) and `stats` is filled in.
+   */
+  StreamEventKind_Done = 1,
+  /**
+   * Generation failed; `text` holds the error message.
+   */
+  StreamEventKind_Error = 2,
+} StreamEventKind;
+
+/**
+ * A conversation with the loaded model.
+ *
+ * The session remembers every token it has fed to the model. As long as the
+ * model's KV cache still belongs to this session, a new turn only prefills
+ * the tokens added since the last reply; otherwise the whole history is
+ * replayed once. Models that can only append one token per pass (Phi, GGUF
+ * and Llamas with rope scaling or tied embeddings) also replay the history
+ * when that is cheaper than a pass per new token.
+ */
+typedef struct ChatSession ChatSession;
+
+/**
+ * A loaded model as seen by the C API. Several can be alive at once, e.g.
+ * an embedding model next to a chat model; each has its own lock, KV cache
+ * and cancellation token.
+ */
+typedef struct ModelHandle ModelHandle;
+
+/**
+ * Timing and token counts of one generation run.
+ */
+typedef struct GenerationStats {
+  uint32_t prompt_tokens;
+  uint32_t generated_tokens;
+  uint64_t elapsed_ms;
+  double tokens_per_second;
+  enum FinishReason finish_reason;
+} GenerationStats;
+
+/**
+ * Event passed to a `StreamCallback`. `text` is only valid for the duration
+ * of the callback and must be copied if it is needed afterwards.
+ */
+typedef struct StreamEvent {
+  enum StreamEventKind kind;
+  /**
+   * `Ok` except for `Error` events and the `Done` event of a cancelled run.
+   */
+  enum LlmStatus status;
+  const char *text;
+  struct GenerationStats stats;
+} StreamEvent;
+
+/**
+ * Sets the directory models are downloaded to and loaded from, e.g. the
+ * app's documents directory on iOS/Android; call it before anything else.
+ * Models go to `<root>/models/<org>/<name>` and the Hub's download cache to
+ * `<root>/hub-cache`. Pass null to fall back to `$LLM_RUNNER_HOME`, or to
+ * `models` in the working directory if that is unset.
+ */
+enum LlmStatus set_storage_root_c(const char *path);
+
+enum LlmStatus download_model_c(const char *model_name);
+
+enum LlmStatus load_model_c(const char *model_name);
+
+/**
+ * Downloads a quantized GGUF file, e.g. `TheBloke/TinyLlama-1.1B-Chat-v1.0-GGUF`
+ * and `tinyllama-1.1b-chat-v1.0.Q4_K_M.gguf`. The tokenizer files come from
+ * `tokenizer_repo`, or from `repo_id` when it is null.
+ */
+enum LlmStatus download_gguf_model_c(const char *repo_id,
+                                     const char *filename,
+                                     const char *tokenizer_repo);
+
+/**
+ * Like `load_model_c`, for a quantized GGUF file. Arguments are the same as
+ * for `download_gguf_model_c`.
+ */
+enum LlmStatus load_gguf_model_c(const char *repo_id,
+                                 const char *filename,
+                                 const char *tokenizer_repo);
+
+/**
+ * Drops the loaded model, freeing its weights, memory maps and KV cache,
+ * e.g. when the app goes to the background. A running generation is
+ * cancelled; its memory is released once that call returns. Chat sessions
+ * stay valid and replay their history on the next load. Succeeds if no
+ * model was loaded.
+ */
+enum LlmStatus unload_model_c(void);
+
+/**
+ * Runs the prompt with the model's default settings and stores the
+ * response in `*out` (free it with `free_string_c`). If the run was
+ * cancelled the status is `Cancelled` and `*out` holds the partial response.
+ */
+enum LlmStatus run_inference_c(const char *input, char **out);
+
+/**
+ * Like `run_inference_c`, but with sampling settings passed as a JSON object,
+ * e.g. `{"temperature": 0.7, "top_k": 40, "top_p": 0.9, "min_p": 0.05, "seed": 42,
+ * "repetition_penalty": 1.1, "frequency_penalty": 0.2, "presence_penalty": 0.1,
+ * "penalty_last_n": 64, "max_new_tokens": 256, "stop": ["\nUser:"],
+ * "context_overflow": "truncate_middle"}`. `context_overflow` is "error" (default),
+ * "truncate_oldest" or "truncate_middle".
+ * Missing fields fall back to the model's defaults (its `generation_config.json`,
+ * then the built-in ones); a null `params_json` uses all defaults.
+ */
+enum LlmStatus run_inference_with_params_c(const char *input, const char *params_json, char **out);
+
+/**
+ * Streams the response through `callback`: one `Token` event per decoded
+ * text fragment, then a single `Done` event carrying the full text and
+ * stats, or an `Error` event with its status code. A cancelled run ends
+ * with a `Done` event holding the partial text, `FinishReason::Cancelled`
+ * and status `Cancelled`. The callback runs on the calling thread before
+ * this function returns, which then returns the same status.
+ * `params_json` is the same as for `run_inference_with_params_c` and may be null.
+ */
+enum LlmStatus run_inference_stream_c(const char *input,
+                                      const char *params_json,
+                                      void (*callback)(const struct StreamEvent *event,
+                                                       void *user_data),
+                                      void *user_data);
+
+/**
+ * Clears the KV cache of the loaded model. Regular inference calls already
+ * start from an empty cache; this frees the cached keys/values by hand.
+ */
+enum LlmStatus reset_cache_c(void);
+
+/**
+ * Stores a JSON description of the loaded model in `*out` (free it with
+ * `free_string_c`): `{"name", "architecture", "context_length",
+ * "cached_tokens", "weights_bytes", "kv_cache_bytes", "memory_bytes"}`.
+ * `memory_bytes` estimates the resident memory of weights plus KV cache.
+ * Does not wait for a running generation.
+ */
+enum LlmStatus model_info_c(char **out);
+
+/**
+ * Overrides the chat template used for `model_name`, now and on future
+ * loads. `template` is Jinja source in Hugging Face's `chat_template`
+ * format; pass null to go back to the model's own template.
+ */
+enum LlmStatus set_chat_template_c(const char *model_name, const char *template_);
+
+/**
+ * Asks the running generation to stop. Safe to call from any thread; the
+ * interrupted call returns (or streams as `Done`) the text generated so far.
+ * Returns false if nothing was running.
+ */
+bool cancel_inference_c(void);
+
+/**
+ * Starts a new chat session. `system_prompt` may be null. The session must
+ * be released with `chat_session_destroy_c`. Returns null on failure.
+ */
+struct ChatSession *chat_session_create_c(const char *system_prompt);
+
+/**
+ * Adds a message to the session. `role` is "system", "user" or "assistant".
+ */
+enum LlmStatus chat_session_append_c(struct ChatSession *session,
+                                     const char *role,
+                                     const char *content);
+
+/**
+ * Generates the assistant's reply to the session's pending messages, stores
+ * it in `*out` (free it with `free_string_c`) and records it in the
+ * conversation. Only the new turn is prefilled while the model's KV cache
+ * still holds this session. `params_json` may be null.
+ */
+enum LlmStatus chat_session_generate_c(struct ChatSession *session,
+                                       const char *params_json,
+                                       char **out);
+
+void chat_session_destroy_c(struct ChatSession *session);
+
+/**
+ * Loads a model from the Hugging Face Hub, like `load_model_c`, but
+ * returns it as a handle instead of replacing the global model. Any number
+ * of models can be loaded this way; release each with `llm_model_free`.
+ * Returns null on failure.
+ */
+struct ModelHandle *llm_model_load(const char *model_name);
+
+/**
+ * Like `llm_model_load`, for a quantized GGUF file. Arguments are the same
+ * as for `download_gguf_model_c`.
+ */
+struct ModelHandle *llm_model_load_gguf(const char *repo_id,
+                                        const char *filename,
+                                        const char *tokenizer_repo);
+
+/**
+ * Releases a handle from `llm_model_load`. No call on it may be running or
+ * start afterwards. Null is ignored.
+ */
+void llm_model_free(struct ModelHandle *model);
+
+/**
+ * `run_inference_with_params_c` on the given model.
+ */
+enum LlmStatus llm_model_run_inference(struct ModelHandle *model,
+                                       const char *input,
+                                       const char *params_json,
+                                       char **out);
+
+/**
+ * `run_inference_stream_c` on the given model.
+ */
+enum LlmStatus llm_model_run_inference_stream(struct ModelHandle *model,
+                                              const char *input,
+                                              const char *params_json,
+                                              void (*callback)(const struct StreamEvent *event,
+                                                               void *user_data),
+                                              void *user_data);
+
+/**
+ * `reset_cache_c` on the given model.
+ */
+enum LlmStatus llm_model_reset_cache(struct ModelHandle *model);
+
+/**
+ * `model_info_c` for the given model.
+ */
+enum LlmStatus llm_model_info(struct ModelHandle *model, char **out);
+
+/**
+ * Replaces the chat template of this model only; pass null to go back to
+ * the model's own template. Use `set_chat_template_c` to also apply it to
+ * future loads.
+ */
+enum LlmStatus llm_model_set_chat_template(struct ModelHandle *model, const char *template_);
+
+/**
+ * `cancel_inference_c` for the given model.
+ */
+bool llm_model_cancel(struct ModelHandle *model);
+
+/**
+ * `chat_session_generate_c` on the given model. A session may move between
+ * models; its history is replayed on the first turn with a new one.
+ */
+enum LlmStatus llm_model_chat_session_generate(struct ModelHandle *model,
+                                               struct ChatSession *session,
+                                               const char *params_json,
+                                               char **out);
+
+/**
+ * Message describing the last failed call on this thread, or null if it
+ * succeeded. The string is owned by the library and stays valid until the
+ * next call on the same thread; do not free it.
+ */
+const char *last_error_c(void);
 
 void free_string_c(char *s);
 
+/**
+ * Tokenizes `text` with the loaded model's tokenizer, special tokens
+ * included, and stores the number of ids in `*length`. Free the array with
+ * `free_array`. Returns null on failure.
+ */
 uint32_t *tokenize_text_c(const char *text, uintptr_t *length);
 
-void free_array(uint32_t *ptr, uintptr_t length);
+/**
+ * Turns `length` token ids back into text with the loaded model's
+ * tokenizer, leaving out special tokens. Free the result with
+ * `free_string_c`. Returns null on failure.
+ */
+char *detokenize_ids(const uint32_t *tokens, uintptr_t length);
+
+/**
+ * Stores the number of tokens `text` takes up with the loaded model's
+ * tokenizer, special tokens included, in `*count`.
+ */
+enum LlmStatus count_tokens_c(const char *text, uintptr_t *count);
 
-void *tokenize_text(const char *_text);
+/**
+ * `tokenize_text_c` with the given model's tokenizer.
+ */
+uint32_t *llm_model_tokenize(struct ModelHandle *model, const char *text, uintptr_t *length);
 
-char *detokenize_ids(const uint32_t *_tokens, uintptr_t _length);
+/**
+ * `detokenize_ids` with the given model's tokenizer.
+ */
+char *llm_model_detokenize(struct ModelHandle *model, const uint32_t *tokens, uintptr_t length);
+
+/**
+ * `count_tokens_c` with the given model's tokenizer.
+ */
+enum LlmStatus llm_model_count_tokens(struct ModelHandle *model,
+                                      const char *text,
+                                      uintptr_t *count);
+
+/**
+ * Tokenizes `text` with the loaded model's tokenizer and stores a JSON
+ * array in `*out` (free it with `free_string_c`), one object per token:
+ * `{"id", "piece", "byte_start", "byte_end", "char_start", "char_end",
+ * "special"}`. Ranges are half-open and point into `text`; `char_*` count
+ * Unicode scalar values. Special tokens added around the text, such as
+ * BOS, are included when `add_special_tokens` is set and have an empty
+ * range at 0.
+ */
+enum LlmStatus tokenize_with_offsets_c(const char *text, bool add_special_tokens, char **out);
+
+/**
+ * `tokenize_with_offsets_c` with the given model's tokenizer.
+ */
+enum LlmStatus llm_model_tokenize_with_offsets(struct ModelHandle *model,
+                                               const char *text,
+                                               bool add_special_tokens,
+                                               char **out);
+
+/**
+ * Stores a JSON description of the loaded model's vocabulary in `*out`
+ * (free it with `free_string_c`): `{"vocab_size", "bos_token_id",
+ * "eos_token_id", "pad_token_id", "unk_token_id", "added_tokens"}`. The
+ * token ids are null when the model has no such token; `added_tokens` is
+ * a list of `{"id", "content", "special"}` sorted by id.
+ */
+enum LlmStatus vocab_info_c(char **out);
+
+/**
+ * Stores the id of `token` in `*id`. `token` is the vocabulary entry, e.g.
+ * `▁hello` or `<|im_end|>`, not text to tokenize.
+ */
+enum LlmStatus token_to_id_c(const char *token, uint32_t *id);
+
+/**
+ * The vocabulary entry for `id`. Free it with `free_string_c`. Returns null
+ * on failure.
+ */
+char *id_to_token_c(uint32_t id);
+
+/**
+ * `vocab_info_c` for the given model.
+ */
+enum LlmStatus llm_model_vocab_info(struct ModelHandle *model, char **out);
+
+/**
+ * `token_to_id_c` with the given model's tokenizer.
+ */
+enum LlmStatus llm_model_token_to_id(struct ModelHandle *model, const char *token, uint32_t *id);
+
+/**
+ * `id_to_token_c` with the given model's tokenizer.
+ */
+char *llm_model_id_to_token(struct ModelHandle *model, uint32_t id);
+
+void free_array(uint32_t *ptr, uintptr_t length);
 
 #endif /* LLM_RUNNER_H */
@@ -0,0 +1,390 @@
+use candle_core::quantized::gguf_file;
+use candle_core::safetensors::MmapedSafetensors;
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::llama::{Cache, Config, Llama, DEFAULT_MAX_SEQ_LEN};
+use candle_transformers::models::{gemma, gemma2, mistral, phi, phi3, qwen2, quantized_llama};
+use serde_json::Value;
+use std::path::Path;
+use crate::config::ModelConfig;
+use crate::error::LlmError;
+
+/// A decoder-only language model with its own KV cache.
+pub trait CausalLM: Send {
+    /// Runs `input` (shape `(1, seq_len)`) with the first token at position
+    /// `offset` and returns the logits of the last position as a 1-D F32
+    /// tensor.
+    fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor>;
+
+    fn clear_kv_cache(&mut self) -> Result<()>;
+
+    /// Whether several tokens can go through `forward` in one pass on top
+    /// of a filled cache. Models that cannot mask them against the cache
+    /// run one pass per token instead.
+    fn appends_in_one_pass(&self) -> bool {
+        true
+    }
+}
+
+/// Model families we can run, detected from `config.json`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+    Llama,
+    Mistral,
+    Qwen2,
+    Gemma,
+    Gemma2,
+    Phi,
+    Phi3,
+}
+
+impl Architecture {
+    /// Reads `architectures` (falling back to `model_type`) from a
+    /// `config.json`. Done before full parsing so that configs of other
+    /// families fail with a clear message rather than a missing field.
+    pub fn detect(config_path: &Path) -> crate::error::Result<Self> {
+        let config_str = std::fs::read_to_string(config_path)
+            .map_err(|e| LlmError::Io(format!("Failed to read config: {}", e)))?;
+        let config: Value = serde_json::from_str(&config_str)
+            .map_err(|e| LlmError::Config(format!("Failed to parse config: {}", e)))?;
+
+        let architecture = config["architectures"][0].as_str().unwrap_or_default();
+        let model_type = config["model_type"].as_str().unwrap_or_default();
+
+        let detected = match architecture {
+            "LlamaForCausalLM" => Some(Architecture::Llama),
+            "MistralForCausalLM" => Some(Architecture::Mistral),
+            "Qwen2ForCausalLM" => Some(Architecture::Qwen2),
+            "GemmaForCausalLM" => Some(Architecture::Gemma),
+            "Gemma2ForCausalLM" => Some(Architecture::Gemma2),
+            "PhiForCausalLM" => Some(Architecture::Phi),
+            "Phi3ForCausalLM" => Some(Architecture::Phi3),
+            _ => None,
+        };
+        let detected = detected.or(match model_type {
+            "llama" => Some(Architecture::Llama),
+            "mistral" => Some(Architecture::Mistral),
+            "qwen2" => Some(Architecture::Qwen2),
+            "gemma" => Some(Architecture::Gemma),
+            "gemma2" => Some(Architecture::Gemma2),
+            "phi" => Some(Architecture::Phi),
+            "phi3" => Some(Architecture::Phi3),
+            _ => None,
+        });
+
+        detected.ok_or_else(|| {
+            LlmError::Config(format!(
+                "Unsupported model architecture '{}' (model_type '{}'). Supported families: \
+                 llama, mistral, qwen2, gemma, gemma2, phi, phi3",
+                architecture, model_type
+            ))
+        })
+    }
+
+    /// Gemma overflows in F16, so it runs in BF16 like upstream.
+    pub fn dtype(&self) -> DType {
+        match self {
+            Architecture::Gemma | Architecture::Gemma2 => DType::BF16,
+            _ => DType::F16,
+        }
+    }
+
+    /// Builds the candle model for this family from `config.json` and weights.
+    pub fn load(&self, config_path: &Path, config: &ModelConfig, vb: VarBuilder) -> crate::error::Result<Box<dyn CausalLM>> {
+        let config_str = std::fs::read_to_string(config_path)
+            .map_err(|e| LlmError::Io(format!("Failed to read config: {}", e)))?;
+        let load_error = |e: candle_core::Error| LlmError::Load(e.to_string());
+
+        Ok(match self {
+            Architecture::Llama => match config.to_mistral() {
+                Some(mistral_config) => Box::new(mistral::Model::new(&mistral_config, vb).map_err(load_error)?),
+                None => Box::new(LlamaModel::new(vb, config.to_llama()?).map_err(load_error)?),
+            },
+            Architecture::Mistral => Box::new(mistral::Model::new(&parse_config(&config_str)?, vb).map_err(load_error)?),
+            Architecture::Qwen2 => Box::new(qwen2::ModelForCausalLM::new(&parse_config(&config_str)?, vb).map_err(load_error)?),
+            Architecture::Gemma => Box::new(gemma::Model::new(false, &parse_config(&config_str)?, vb).map_err(load_error)?),
+            Architecture::Gemma2 => Box::new(gemma2::Model::new(false, &parse_config(&config_str)?, vb).map_err(load_error)?),
+            Architecture::Phi => Box::new(phi::Model::new(&parse_config(&config_str)?, vb).map_err(load_error)?),
+            Architecture::Phi3 => Box::new(phi3::Model::new(&parse_config(&config_str)?, vb).map_err(load_error)?),
+        })
+    }
+}
+
+/// Rough memory use of a loaded model.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryFootprint {
+    /// Size of the weights as held in memory, after any dtype conversion.
+    pub weights_bytes: usize,
+    /// Bytes the KV cache grows by per cached token.
+    pub kv_cache_bytes_per_token: usize,
+}
+
+/// Size of the weights in `paths` once converted to `dtype`. Only the
+/// safetensors headers are read.
+pub fn safetensors_weights_bytes(paths: &[impl AsRef<Path>], dtype: DType) -> crate::error::Result<usize> {
+    let safetensors = unsafe { MmapedSafetensors::multi(paths) }
+        .map_err(|e| LlmError::Load(e.to_string()))?;
+    Ok(safetensors
+        .tensors()
+        .iter()
+        .map(|(_, view)| view.shape().iter().product::<usize>() * dtype.size_in_bytes())
+        .sum())
+}
+
+/// A model read from a GGUF file.
+pub struct GgufModel {
+    pub architecture: Architecture,
+    pub model: Box<dyn CausalLM>,
+    /// Context length stored in the file.
+    pub context_length: usize,
+    pub memory: MemoryFootprint,
+}
+
+/// Loads a quantized GGUF file (Q4_0, Q4_K_M, Q8_0, ...). Only Llama-style
+/// files, which includes most Mistral and TinyLlama conversions, are
+/// supported.
+pub fn load_gguf(path: &Path, device: &Device) -> crate::error::Result<GgufModel> {
+    let mut file = std::fs::File::open(path)
+        .map_err(|e| LlmError::Io(format!("Failed to open {}: {}", path.display(), e)))?;
+    let content = gguf_file::Content::read(&mut file)
+        .map_err(|e| LlmError::Load(format!("Failed to read GGUF file: {}", e)))?;
+
+    let architecture = content
+        .metadata
+        .get("general.architecture")
+        .and_then(|v| v.to_string().ok())
+        .cloned()
+        .unwrap_or_default();
+    if architecture != "llama" {
+        return Err(LlmError::Config(format!(
+            "Unsupported GGUF architecture '{}'. Only llama GGUF files are supported",
+            architecture
+        )));
+    }
+    if let Some(info) = content.tensor_infos.get("token_embd.weight") {
+        println!("Loading GGUF model with {} tensors ({:?} embeddings)", content.tensor_infos.len(), info.ggml_dtype);
+    }
+
+    let context_length = content
+        .metadata
+        .get("llama.context_length")
+        .and_then(|v| v.to_u32().ok())
+        .map(|v| v as usize)
+        .unwrap_or(DEFAULT_MAX_SEQ_LEN);
+
+    let memory = gguf_memory_footprint(&content);
+
+    let model = quantized_llama::ModelWeights::from_gguf(content, &mut file, device)
+        .map_err(|e| LlmError::Load(e.to_string()))?;
+    Ok(GgufModel {
+        architecture: Architecture::Llama,
+        model: Box::new(QuantizedLlama::new(model)),
+        context_length,
+        memory,
+    })
+}
+
+/// Quantized weights stay quantized in memory; the KV cache is F32.
+fn gguf_memory_footprint(content: &gguf_file::Content) -> MemoryFootprint {
+    let weights_bytes = content
+        .tensor_infos
+        .values()
+        .map(|info| info.shape.elem_count() / info.ggml_dtype.block_size() * info.ggml_dtype.type_size())
+        .sum();
+
+    let metadata = |key: &str| content.metadata.get(key).and_then(|v| v.to_u32().ok()).unwrap_or(0) as usize;
+    let heads = metadata("llama.attention.head_count");
+    let kv_heads = match metadata("llama.attention.head_count_kv") {
+        0 => heads,
+        n => n,
+    };
+    let head_dim = metadata("llama.embedding_length").checked_div(heads).unwrap_or(0);
+    let kv_cache_bytes_per_token = 2 * metadata("llama.block_count") * kv_heads * head_dim * DType::F32.size_in_bytes();
+
+    MemoryFootprint { weights_bytes, kv_cache_bytes_per_token }
+}
+
+fn parse_config<T: serde::de::DeserializeOwned>(config_str: &str) -> crate::error::Result<T> {
+    serde_json::from_str(config_str)
+        .map_err(|e| LlmError::Config(format!("Failed to parse config: {}", e)))
+}
+
+/// Flattens `(1, vocab)` or `(1, 1, vocab)` logits to `(vocab)`.
+fn last_logits(logits: Tensor) -> Result<Tensor> {
+    logits.flatten_all()?.to_dtype(DType::F32)
+}
+
+/// candle's Llama keeps its cache outside the model. Only used for what
+/// its Mistral cannot run, see `ModelConfig::to_mistral`.
+pub struct LlamaModel {
+    model: Llama,
+    cache: Cache,
+    config: Config,
+    dtype: DType,
+    device: Device,
+}
+
+impl LlamaModel {
+    pub fn new(vb: VarBuilder, config: Config) -> Result<Self> {
+        let dtype = vb.dtype();
+        let device = vb.device().clone();
+        let cache = Cache::new(true, dtype, &config, &device)?;
+        let model = Llama::load(vb, &config)?;
+        Ok(LlamaModel { model, cache, config, dtype, device })
+    }
+}
+
+impl CausalLM for LlamaModel {
+    fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        // Llama only builds a causal mask for prompts that start at position
+        // 0, so anything fed on top of a filled cache goes one by one
+        let (_, seq_len) = input.dims2()?;
+        if offset > 0 && seq_len > 1 {
+            let mut logits = None;
+            for i in 0..seq_len {
+                logits = Some(self.forward(&input.narrow(1, i, 1)?, offset + i)?);
+            }
+            return logits.ok_or_else(|| candle_core::Error::Msg("No tokens to feed".to_string()));
+        }
+        last_logits(self.model.forward(input, offset, &mut self.cache)?)
+    }
+
+    fn clear_kv_cache(&mut self) -> Result<()> {
+        self.cache = Cache::new(true, self.dtype, &self.config, &self.device)?;
+        Ok(())
+    }
+
+    fn appends_in_one_pass(&self) -> bool {
+        false
+    }
+}
+
+impl CausalLM for phi::Model {
+    fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        // Phi tracks positions itself and, like Llama, cannot mask a
+        // multi-token input against an existing cache
+        let (_, seq_len) = input.dims2()?;
+        if offset > 0 && seq_len > 1 {
+            let mut logits = None;
+            for i in 0..seq_len {
+                logits = Some(phi::Model::forward(self, &input.narrow(1, i, 1)?)?);
+            }
+            return last_logits(logits.ok_or_else(|| candle_core::Error::Msg("No tokens to feed".to_string()))?);
+        }
+        last_logits(phi::Model::forward(self, input)?)
+    }
+
+    fn clear_kv_cache(&mut self) -> Result<()> {
+        phi::Model::clear_kv_cache(self);
+        Ok(())
+    }
+
+    fn appends_in_one_pass(&self) -> bool {
+        false
+    }
+}
+
+/// A quantized Llama. candle's `ModelWeights` only drops its layer caches
+/// on the next pass at position 0, so a copy taken before any forward pass
+/// is kept to swap back in. The copy shares the weights.
+pub struct QuantizedLlama {
+    weights: quantized_llama::ModelWeights,
+    empty: quantized_llama::ModelWeights,
+}
+
+impl QuantizedLlama {
+    pub fn new(weights: quantized_llama::ModelWeights) -> Self {
+        QuantizedLlama { empty: weights.clone(), weights }
+    }
+}
+
+impl CausalLM for QuantizedLlama {
+    fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+        // Same masking limitation as the F16 Llama
+        let (_, seq_len) = input.dims2()?;
+        if offset > 0 && seq_len > 1 {
+            let mut logits = None;
+            for i in 0..seq_len {
+                logits = Some(self.weights.forward(&input.narrow(1, i, 1)?, offset + i)?);
+            }
+            return last_logits(logits.ok_or_else(|| candle_core::Error::Msg("No tokens to feed".to_string()))?);
+        }
+        last_logits(self.weights.forward(input, offset)?)
+    }
+
+    fn clear_kv_cache(&mut self) -> Result<()> {
+        self.weights = self.empty.clone();
+        Ok(())
+    }
+
+    fn appends_in_one_pass(&self) -> bool {
+        false
+    }
+}
+
+/// Families whose candle model already takes a position offset and owns its cache.
+macro_rules! impl_causal_lm {
+    ($($model:ty),*) => {
+        $(
+            impl CausalLM for $model {
+                fn forward(&mut self, input: &Tensor, offset: usize) -> Result<Tensor> {
+                    last_logits(<$model>::forward(self, input, offset)?)
+                }
+
+                fn clear_kv_cache(&mut self) -> Result<()> {
+                    <$model>::clear_kv_cache(self);
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+impl_causal_lm!(mistral::Model, qwen2::ModelForCausalLM, gemma::Model, gemma2::Model, phi3::Model);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_nn::VarMap;
+
+    const TINY_LLAMA: &str = r#"{
+        "hidden_size": 64,
+        "intermediate_size": 128,
+        "vocab_size": 100,
+        "num_hidden_layers": 2,
+        "num_attention_heads": 4,
+        "num_key_value_heads": 2,
+        "max_position_embeddings": 64
+    }"#;
+
+    fn max_difference(a: &Tensor, b: &Tensor) -> f32 {
+        (a - b).unwrap().abs().unwrap().max(0).unwrap().to_scalar().unwrap()
+    }
+
+    #[test]
+    fn mistral_runs_llama_weights_and_appends_in_one_pass() {
+        let config = ModelConfig::parse(TINY_LLAMA).unwrap();
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let mut mistral = mistral::Model::new(&config.to_mistral().unwrap(), vb.clone()).unwrap();
+        // Same weights, looked up under the same names
+        let mut llama = LlamaModel::new(vb, config.to_llama().unwrap()).unwrap();
+        assert!(CausalLM::appends_in_one_pass(&mistral));
+
+        let tokens = |ids: &[u32]| Tensor::new(ids, &Device::Cpu).unwrap().unsqueeze(0).unwrap();
+        let prompt = tokens(&[1, 5, 9, 14]);
+        let turn = tokens(&[20, 7, 3]);
+        let expected = [
+            CausalLM::forward(&mut llama, &prompt, 0).unwrap(),
+            CausalLM::forward(&mut llama, &turn, 4).unwrap(),
+        ];
+        let actual = [
+            CausalLM::forward(&mut mistral, &prompt, 0).unwrap(),
+            CausalLM::forward(&mut mistral, &turn, 4).unwrap(),
+        ];
+        for (expected, actual) in expected.iter().zip(&actual) {
+            assert_eq!(actual.dims(), &[100]);
+            assert!(max_difference(expected, actual) < 1e-4);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use lazy_static::lazy_static;
+use minijinja::{context, Environment, ErrorKind};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use tokenizers::Tokenizer;
+use crate::error::{LlmError, Result};
+use crate::session::ChatMessage;
+
+/// Prompt format used when a model ships no `chat_template`.
+pub const DEFAULT_TEMPLATE: &str = "{{ bos_token }}\
+{% for message in messages %}\
+{% if message.role == 'system' %}### System: {{ message.content }}\n\
+{% elif message.role == 'user' %}### Human: {{ message.content }}\n\
+{% else %}### Assistant: {{ message.content }}\n\
+{% endif %}\
+{% endfor %}\
+{% if add_generation_prompt %}### Assistant:{% endif %}";
+
+lazy_static! {
+    // Templates set by the host app, keyed by model name
+    static ref OVERRIDES: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// Replaces the chat template of `model_name` (`None` restores the model's own).
+pub fn set_override(model_name: &str, source: Option<&str>) {
+    let mut overrides = crate::lock(&OVERRIDES);
+    match source {
+        Some(source) => overrides.insert(model_name.to_string(), source.to_string()),
+        None => overrides.remove(model_name),
+    };
+}
+
+pub fn override_for(model_name: &str) -> Option<String> {
+    crate::lock(&OVERRIDES).get(model_name).cloned()
+}
+
+/// A Jinja chat template, rendered the way Hugging Face's
+/// `apply_chat_template` does it.
+pub struct ChatTemplate {
+    env: Environment<'static>,
+    pub bos_token: String,
+    pub eos_token: String,
+}
+
+impl ChatTemplate {
+    pub fn new(source: &str, bos_token: &str, eos_token: &str) -> Result<Self> {
+        let mut env = Environment::new();
+        // Same whitespace handling as transformers' Jinja environment
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", |message: String| -> std::result::Result<String, minijinja::Error> {
+            Err(minijinja::Error::new(ErrorKind::InvalidOperation, message))
+        });
+        env.add_template_owned("chat", source.to_string())
+            .map_err(|e| LlmError::Template(format!("Invalid chat template: {}", e)))?;
+
+        Ok(ChatTemplate {
+            env,
+            bos_token: bos_token.to_string(),
+            eos_token: eos_token.to_string(),
+        })
+    }
+
+    /// Reads `chat_template`, `bos_token` and `eos_token` from a
+    /// `tokenizer_config.json`. Falls back to `DEFAULT_TEMPLATE` when the
+    /// file or the template is missing, and to the BOS/EOS tokens `tokenizer`
+    /// knows about when the file does not name them.
+    pub fn from_tokenizer_config(path: &Path, tokenizer: &Tokenizer) -> Result<Self> {
+        let config: Value = match std::fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str(&s)
+                .map_err(|e| LlmError::Config(format!("Failed to parse tokenizer config: {}", e)))?,
+            Err(_) => Value::Null,
+        };
+
+        let source = match &config["chat_template"] {
+            Value::String(template) => template.clone(),
+            // Newer repos ship a list of named templates
+            Value::Array(templates) => templates
+                .iter()
+                .find(|t| t["name"] == "default")
+                .or_else(|| templates.first())
+                .and_then(|t| t["template"].as_str())
+                .unwrap_or(DEFAULT_TEMPLATE)
+                .to_string(),
+            _ => DEFAULT_TEMPLATE.to_string(),
+        };
+
+        let (default_bos, default_eos) = default_special_tokens(tokenizer);
+        let bos_token = Some(special_token(&config["bos_token"])).filter(|t| !t.is_empty()).or(default_bos);
+        let eos_token = Some(special_token(&config["eos_token"])).filter(|t| !t.is_empty()).or(default_eos);
+        Self::new(&source, &bos_token.unwrap_or_default(), &eos_token.unwrap_or_default())
+    }
+
+    pub fn render(&self, messages: &[ChatMessage], add_generation_prompt: bool) -> Result<String> {
+        let template = self.env.get_template("chat")
+            .map_err(|e| LlmError::Template(format!("Invalid chat template: {}", e)))?;
+        template
+            .render(context! {
+                messages => messages,
+                add_generation_prompt => add_generation_prompt,
+                bos_token => &self.bos_token,
+                eos_token => &self.eos_token,
+            })
+            .map_err(|e| LlmError::Template(format!("Failed to render chat template: {}", e)))
+    }
+}
+
+/// Special tokens are either plain strings or `{"content": "..."}` objects.
+pub(crate) fn special_token(value: &Value) -> String {
+    match value {
+        Value::String(token) => token.clone(),
+        Value::Object(token) => token.get("content").and_then(Value::as_str).unwrap_or_default().to_string(),
+        _ => String::new(),
+    }
+}
+
+/// BOS/EOS tokens for a model without a `tokenizer_config.json`: the ones
+/// the tokenizer's post processor puts around a sequence, else `<s>` and
+/// `</s>` if the vocabulary has them.
+fn default_special_tokens(tokenizer: &Tokenizer) -> (Option<String>, Option<String>) {
+    let added = tokenizer
+        .encode("", true)
+        .map(|encoding| encoding.get_tokens().to_vec())
+        .unwrap_or_default();
+    let known = |token: &str| tokenizer.token_to_id(token).map(|_| token.to_string());
+
+    let bos = added.first().cloned().or_else(|| known("<s>"));
+    let eos = match added.as_slice() {
+        [_, .., last] => Some(last.clone()),
+        _ => known("</s>"),
+    };
+    (bos, eos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Role;
+    use crate::tokenizer::tests::word_level;
+
+    // As shipped in the models' tokenizer_config.json
+    const CHATML: &str = "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}";
+    const ZEPHYR: &str = "{% for message in messages %}\n{% if message['role'] == 'user' %}\n{{ '<|user|>\n' + message['content'] + eos_token }}\n{% elif message['role'] == 'system' %}\n{{ '<|system|>\n' + message['content'] + eos_token }}\n{% elif message['role'] == 'assistant' %}\n{{ '<|assistant|>\n'  + message['content'] + eos_token }}\n{% endif %}\n{% if loop.last and add_generation_prompt %}\n{{ '<|assistant|>' }}\n{% endif %}\n{% endfor %}";
+    const LLAMA_2: &str = "{% if messages[0]['role'] == 'system' %}{% set loop_messages = messages[1:] %}{% set system_message = messages[0]['content'] %}{% else %}{% set loop_messages = messages %}{% set system_message = false %}{% endif %}{% for message in loop_messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if loop.index0 == 0 and system_message != false %}{% set content = '<<SYS>>\\n' + system_message + '\\n<</SYS>>\\n\\n' + message['content'] %}{% else %}{% set content = message['content'] %}{% endif %}{% if message['role'] == 'user' %}{{ bos_token + '[INST] ' + content.strip() + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ ' '  + content.strip() + ' ' + eos_token }}{% endif %}{% endfor %}";
+
+    fn messages(messages: &[(Role, &str)]) -> Vec<ChatMessage> {
+        messages.iter().map(|(role, content)| ChatMessage { role: *role, content: content.to_string() }).collect()
+    }
+
+    #[test]
+    fn renders_chatml() {
+        let template = ChatTemplate::new(CHATML, "", "<|im_end|>").unwrap();
+        let chat = messages(&[(Role::System, "You are helpful."), (Role::User, "Hi")]);
+        assert_eq!(
+            template.render(&chat, true).unwrap(),
+            "<|im_start|>system\nYou are helpful.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(
+            template.render(&chat, false).unwrap(),
+            "<|im_start|>system\nYou are helpful.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n"
+        );
+    }
+
+    #[test]
+    fn renders_zephyr_with_trimmed_blocks() {
+        let template = ChatTemplate::new(ZEPHYR, "<s>", "</s>").unwrap();
+        let chat = messages(&[(Role::System, "You are helpful."), (Role::User, "Hi"), (Role::Assistant, "Hello!"), (Role::User, "Bye")]);
+        assert_eq!(
+            template.render(&chat, true).unwrap(),
+            "<|system|>\nYou are helpful.</s>\n<|user|>\nHi</s>\n<|assistant|>\nHello!</s>\n<|user|>\nBye</s>\n<|assistant|>\n"
+        );
+    }
+
+    #[test]
+    fn renders_llama_2_with_python_string_methods() {
+        let template = ChatTemplate::new(LLAMA_2, "<s>", "</s>").unwrap();
+        let chat = messages(&[(Role::System, "Be brief."), (Role::User, "Hi "), (Role::Assistant, " Hello! "), (Role::User, "Bye")]);
+        assert_eq!(
+            template.render(&chat, true).unwrap(),
+            "<s>[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi [/INST] Hello! </s><s>[INST] Bye [/INST]"
+        );
+    }
+
+    #[test]
+    fn reports_raise_exception_as_a_template_error() {
+        let template = ChatTemplate::new(LLAMA_2, "<s>", "</s>").unwrap();
+        let chat = messages(&[(Role::User, "Hi"), (Role::User, "again")]);
+        match template.render(&chat, true) {
+            Err(LlmError::Template(message)) => assert!(message.contains("Conversation roles must alternate"), "{}", message),
+            other => panic!("expected a template error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_tokenizers_special_tokens() {
+        let template = ChatTemplate::from_tokenizer_config(Path::new("missing/tokenizer_config.json"), &word_level()).unwrap();
+        assert_eq!(template.bos_token, "<s>");
+        assert_eq!(template.eos_token, "</s>");
+
+        let messages = [ChatMessage { role: Role::User, content: "hello".to_string() }];
+        assert!(template.render(&messages, true).unwrap().starts_with("<s>"));
+    }
+}
@@ -0,0 +1,510 @@
+use candle_core::DType;
+use candle_nn::Activation;
+use candle_transformers::models::llama::{self, Llama3RopeConfig, Llama3RopeType, LlamaEosToks, DEFAULT_MAX_SEQ_LEN};
+use candle_transformers::models::mistral;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::path::Path;
+use crate::error::{LlmError, Result};
+use crate::sampler::SamplingParams;
+
+/// The fields of a Hugging Face `config.json` that every supported family
+/// shares. Family-specific settings are left to candle's own configs.
+#[derive(Debug, Clone)]
+pub struct ModelConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub vocab_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    /// Defaults to `num_attention_heads` (no grouped-query attention).
+    pub num_key_value_heads: Option<usize>,
+    /// Size of one attention head. Defaults to `hidden_size /
+    /// num_attention_heads`; Gemma sets it explicitly.
+    pub head_dim: Option<usize>,
+    pub rms_norm_eps: f64,
+    pub rope_theta: f32,
+    /// Context window of the model.
+    pub max_position_embeddings: usize,
+    pub rope_scaling: Option<RopeScaling>,
+    pub tie_word_embeddings: bool,
+    pub bos_token_id: Option<u32>,
+    pub eos_token_id: Option<TokenIds>,
+}
+
+/// `rope_scaling` as written by transformers. Older configs name the kind
+/// `type` instead of `rope_type`.
+#[derive(Debug, Clone)]
+pub struct RopeScaling {
+    pub rope_type: String,
+    pub factor: Option<f32>,
+    pub low_freq_factor: Option<f32>,
+    pub high_freq_factor: Option<f32>,
+    pub original_max_position_embeddings: Option<usize>,
+}
+
+/// A single token id or a list of them, as used by `eos_token_id`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum TokenIds {
+    Single(u32),
+    Multiple(Vec<u32>),
+}
+
+impl TokenIds {
+    pub fn ids(&self) -> Vec<u32> {
+        match self {
+            TokenIds::Single(id) => vec![*id],
+            TokenIds::Multiple(ids) => ids.clone(),
+        }
+    }
+}
+
+/// Sampling defaults and stop tokens from a `generation_config.json`.
+/// Every field is optional; a missing file behaves like an empty one.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GenerationConfig {
+    pub eos_token_id: Option<TokenIds>,
+    pub do_sample: Option<bool>,
+    pub temperature: Option<f32>,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f32>,
+    pub repetition_penalty: Option<f32>,
+    pub max_new_tokens: Option<usize>,
+}
+
+impl GenerationConfig {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let config_str = match std::fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(_) => return Ok(Self::default()),
+        };
+        serde_json::from_str(&config_str)
+            .map_err(|e| LlmError::Config(format!("Invalid generation_config.json: {}", e)))
+    }
+
+    pub fn eos_token_ids(&self) -> Vec<u32> {
+        self.eos_token_id.as_ref().map(TokenIds::ids).unwrap_or_default()
+    }
+
+    /// Returns `base` with the settings this file specifies.
+    pub fn sampling_defaults(&self, base: SamplingParams) -> SamplingParams {
+        let mut params = base;
+        if let Some(temperature) = self.temperature {
+            params.temperature = temperature;
+        }
+        if let Some(top_k) = self.top_k {
+            params.top_k = top_k;
+        }
+        if let Some(top_p) = self.top_p {
+            params.top_p = top_p;
+        }
+        if let Some(repetition_penalty) = self.repetition_penalty {
+            params.repetition_penalty = repetition_penalty;
+        }
+        if let Some(max_new_tokens) = self.max_new_tokens {
+            params.max_new_tokens = max_new_tokens;
+        }
+        // transformers decodes greedily unless sampling is switched on
+        if self.do_sample == Some(false) {
+            params.temperature = 0.0;
+        }
+        params
+    }
+}
+
+impl ModelConfig {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let config_str = std::fs::read_to_string(path)
+            .map_err(|e| LlmError::Io(format!("Failed to read config: {}", e)))?;
+        Self::parse(&config_str)
+    }
+
+    /// Parses and validates a `config.json`. Errors name the offending field
+    /// by its full path, e.g. `rope_scaling.factor`.
+    pub fn parse(config_str: &str) -> Result<Self> {
+        let value: Value = serde_json::from_str(config_str)
+            .map_err(|e| LlmError::Config(format!("Invalid config.json: {}", e)))?;
+        let fields = Fields::of(&value, "")?;
+        let config = ModelConfig {
+            hidden_size: fields.required("hidden_size")?,
+            intermediate_size: fields.required("intermediate_size")?,
+            vocab_size: fields.required("vocab_size")?,
+            num_hidden_layers: fields.required("num_hidden_layers")?,
+            num_attention_heads: fields.required("num_attention_heads")?,
+            num_key_value_heads: fields.optional("num_key_value_heads")?,
+            head_dim: fields.optional("head_dim")?,
+            rms_norm_eps: fields.optional("rms_norm_eps")?.unwrap_or(1e-5),
+            rope_theta: fields.optional("rope_theta")?.unwrap_or(10_000.0),
+            max_position_embeddings: fields.optional("max_position_embeddings")?.unwrap_or(DEFAULT_MAX_SEQ_LEN),
+            rope_scaling: fields.section("rope_scaling")?.map(RopeScaling::parse).transpose()?,
+            tie_word_embeddings: fields.optional("tie_word_embeddings")?.unwrap_or(false),
+            bos_token_id: fields.optional("bos_token_id")?,
+            eos_token_id: fields.optional("eos_token_id")?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn num_key_value_heads(&self) -> usize {
+        self.num_key_value_heads.unwrap_or(self.num_attention_heads)
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.head_dim.unwrap_or(self.hidden_size / self.num_attention_heads)
+    }
+
+    pub fn eos_token_ids(&self) -> Vec<u32> {
+        self.eos_token_id.as_ref().map(TokenIds::ids).unwrap_or_default()
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (field, value) in [
+            ("hidden_size", self.hidden_size),
+            ("intermediate_size", self.intermediate_size),
+            ("vocab_size", self.vocab_size),
+            ("num_hidden_layers", self.num_hidden_layers),
+            ("num_attention_heads", self.num_attention_heads),
+            ("num_key_value_heads", self.num_key_value_heads()),
+            ("max_position_embeddings", self.max_position_embeddings),
+        ] {
+            if value == 0 {
+                return Err(config_error(field, "must be greater than 0"));
+            }
+        }
+        if self.head_dim == Some(0) {
+            return Err(config_error("head_dim", "must be greater than 0"));
+        }
+        if !self.num_attention_heads.is_multiple_of(self.num_key_value_heads()) {
+            return Err(config_error(
+                "num_key_value_heads",
+                &format!(
+                    "{} does not divide num_attention_heads ({})",
+                    self.num_key_value_heads(),
+                    self.num_attention_heads
+                ),
+            ));
+        }
+        if let Some(TokenIds::Multiple(ids)) = &self.eos_token_id {
+            if ids.is_empty() {
+                return Err(config_error("eos_token_id", "must not be an empty list"));
+            }
+        }
+        for (field, id) in self.bos_token_id.iter().map(|id| ("bos_token_id", *id))
+            .chain(self.eos_token_ids().into_iter().map(|id| ("eos_token_id", id)))
+        {
+            if id as usize >= self.vocab_size {
+                return Err(config_error(field, &format!("{} is outside the vocabulary ({})", id, self.vocab_size)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes the KV cache grows by per token when it is stored as `dtype`.
+    pub fn kv_cache_bytes_per_token(&self, dtype: DType) -> usize {
+        2 * self.num_hidden_layers * self.num_key_value_heads() * self.head_dim() * dtype.size_in_bytes()
+    }
+
+    /// Builds candle's Llama config. Only Llama 3 style `rope_scaling` is
+    /// supported by that implementation, and no explicit `head_dim`.
+    pub fn to_llama(&self) -> Result<llama::Config> {
+        if !self.hidden_size.is_multiple_of(self.num_attention_heads) {
+            return Err(config_error(
+                "hidden_size",
+                &format!("{} is not a multiple of num_attention_heads ({})", self.hidden_size, self.num_attention_heads),
+            ));
+        }
+        if self.head_dim.is_some_and(|head_dim| head_dim != self.hidden_size / self.num_attention_heads) {
+            return Err(config_error("head_dim", "must be hidden_size / num_attention_heads for this model"));
+        }
+
+        Ok(llama::Config {
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            vocab_size: self.vocab_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            num_key_value_heads: self.num_key_value_heads(),
+            use_flash_attn: false,
+            rms_norm_eps: self.rms_norm_eps,
+            rope_theta: self.rope_theta,
+            bos_token_id: self.bos_token_id,
+            eos_token_id: self.eos_token_id.as_ref().map(|eos| match eos {
+                TokenIds::Single(id) => LlamaEosToks::Single(*id),
+                TokenIds::Multiple(ids) => LlamaEosToks::Multiple(ids.clone()),
+            }),
+            rope_scaling: self.rope_scaling.as_ref().map(RopeScaling::to_llama3).transpose()?.flatten(),
+            max_position_embeddings: self.max_position_embeddings,
+            tie_word_embeddings: self.tie_word_embeddings,
+        })
+    }
+
+    /// Builds a config for candle's Mistral, which runs the same layers as
+    /// its Llama but masks a multi-token input against a filled cache, so a
+    /// new chat turn is appended in one pass. `None` if the model needs what
+    /// only the Llama implementation has: Llama 3 rope scaling or tied
+    /// embeddings.
+    pub fn to_mistral(&self) -> Option<mistral::Config> {
+        let scaled = self.rope_scaling.as_ref().is_some_and(|scaling| scaling.rope_type != "default");
+        if scaled || self.tie_word_embeddings {
+            return None;
+        }
+        Some(mistral::Config {
+            vocab_size: self.vocab_size,
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            head_dim: self.head_dim,
+            num_key_value_heads: self.num_key_value_heads(),
+            hidden_act: Activation::Silu,
+            max_position_embeddings: self.max_position_embeddings,
+            rms_norm_eps: self.rms_norm_eps,
+            rope_theta: self.rope_theta as f64,
+            sliding_window: None,
+            use_flash_attn: false,
+        })
+    }
+}
+
+impl RopeScaling {
+    fn parse(fields: Fields) -> Result<Self> {
+        let rope_type = match fields.optional("rope_type")? {
+            Some(rope_type) => rope_type,
+            None => fields.optional("type")?.ok_or_else(|| config_error(&fields.path("rope_type"), "is required"))?,
+        };
+        Ok(RopeScaling {
+            rope_type,
+            factor: fields.optional("factor")?,
+            low_freq_factor: fields.optional("low_freq_factor")?,
+            high_freq_factor: fields.optional("high_freq_factor")?,
+            original_max_position_embeddings: fields.optional("original_max_position_embeddings")?,
+        })
+    }
+
+    /// `None` for `"default"`, which means no scaling.
+    fn to_llama3(&self) -> Result<Option<Llama3RopeConfig>> {
+        match self.rope_type.as_str() {
+            "llama3" => {}
+            "default" => return Ok(None),
+            other => {
+                return Err(config_error(
+                    "rope_scaling.rope_type",
+                    &format!("'{}' is not supported (expected 'llama3' or 'default')", other),
+                ))
+            }
+        }
+        let field = |name: &str, value: Option<f32>| {
+            value.ok_or_else(|| config_error(&format!("rope_scaling.{}", name), "is required"))
+        };
+
+        Ok(Some(Llama3RopeConfig {
+            factor: field("factor", self.factor)?,
+            low_freq_factor: field("low_freq_factor", self.low_freq_factor)?,
+            high_freq_factor: field("high_freq_factor", self.high_freq_factor)?,
+            original_max_position_embeddings: self.original_max_position_embeddings.ok_or_else(|| {
+                config_error("rope_scaling.original_max_position_embeddings", "is required")
+            })?,
+            rope_type: Llama3RopeType::Llama3,
+        }))
+    }
+}
+
+/// One object of `config.json`. Its fields are deserialized one by one, so
+/// errors can name the field by its full path.
+struct Fields<'a> {
+    object: &'a Map<String, Value>,
+    /// Path of the object itself, empty at the top level.
+    path: String,
+}
+
+impl<'a> Fields<'a> {
+    fn of(value: &'a Value, path: &str) -> Result<Self> {
+        match value.as_object() {
+            Some(object) => Ok(Fields { object, path: path.to_string() }),
+            None if path.is_empty() => Err(LlmError::Config("Invalid config.json: expected a JSON object".to_string())),
+            None => Err(config_error(path, "must be an object")),
+        }
+    }
+
+    fn path(&self, name: &str) -> String {
+        match self.path.as_str() {
+            "" => name.to_string(),
+            parent => format!("{}.{}", parent, name),
+        }
+    }
+
+    /// `None` if the field is missing or null.
+    fn optional<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>> {
+        match self.object.get(name) {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => T::deserialize(value)
+                .map(Some)
+                .map_err(|e| config_error(&self.path(name), &e.to_string())),
+        }
+    }
+
+    fn required<T: DeserializeOwned>(&self, name: &str) -> Result<T> {
+        self.optional(name)?.ok_or_else(|| config_error(&self.path(name), "is required"))
+    }
+
+    /// A nested object, if present.
+    fn section(&self, name: &str) -> Result<Option<Fields<'a>>> {
+        match self.object.get(name) {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => Fields::of(value, &self.path(name)).map(Some),
+        }
+    }
+}
+
+fn config_error(field: &str, message: &str) -> LlmError {
+    LlmError::Config(format!("Invalid config.json: `{}` {}", field, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TINY: &str = r#"{
+        "hidden_size": 64,
+        "intermediate_size": 128,
+        "vocab_size": 100,
+        "num_hidden_layers": 2,
+        "num_attention_heads": 4,
+        "num_key_value_heads": 2,
+        "eos_token_id": 2
+    }"#;
+
+    /// `TINY` with `field` set to the raw JSON `value`.
+    fn with(field: &str, value: &str) -> String {
+        let mut config: serde_json::Value = serde_json::from_str(TINY).unwrap();
+        config[field] = serde_json::from_str(value).unwrap();
+        serde_json::to_string_pretty(&config).unwrap()
+    }
+
+    fn error(config: &str) -> String {
+        match ModelConfig::parse(config) {
+            Err(LlmError::Config(message)) => message,
+            other => panic!("expected a config error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn parses_with_defaults() {
+        let config = ModelConfig::parse(TINY).unwrap();
+        assert_eq!(config.num_key_value_heads(), 2);
+        assert_eq!(config.max_position_embeddings, DEFAULT_MAX_SEQ_LEN);
+        assert_eq!(config.eos_token_ids(), vec![2]);
+        assert_eq!(config.kv_cache_bytes_per_token(DType::F16), 2 * 2 * 2 * 16 * 2);
+    }
+
+    #[test]
+    fn sizes_the_kv_cache_by_an_explicit_head_dim() {
+        // gemma-2-2b: 8 heads of 256 over a hidden size of 2304
+        let config = ModelConfig::parse(r#"{
+            "hidden_size": 2304,
+            "intermediate_size": 9216,
+            "vocab_size": 256000,
+            "num_hidden_layers": 26,
+            "num_attention_heads": 8,
+            "num_key_value_heads": 4,
+            "head_dim": 256
+        }"#).unwrap();
+        assert_eq!(config.kv_cache_bytes_per_token(DType::BF16), 2 * 26 * 4 * 256 * 2);
+        assert_eq!(config.to_mistral().unwrap().head_dim, Some(256));
+        assert!(error(&with("head_dim", "0")).contains("`head_dim` must be greater than 0"));
+    }
+
+    #[test]
+    fn names_missing_fields() {
+        let mut config: serde_json::Value = serde_json::from_str(TINY).unwrap();
+        config.as_object_mut().unwrap().remove("vocab_size");
+        assert_eq!(error(&config.to_string()), "Invalid config.json: `vocab_size` is required");
+
+        let config = with("rope_scaling", r#"{"factor": 8.0}"#);
+        assert_eq!(error(&config), "Invalid config.json: `rope_scaling.rope_type` is required");
+    }
+
+    #[test]
+    fn names_fields_of_the_wrong_type() {
+        assert!(error(&with("hidden_size", r#""64""#)).starts_with("Invalid config.json: `hidden_size` invalid type"));
+        assert!(error(&with("eos_token_id", r#"[2, "x"]"#)).starts_with("Invalid config.json: `eos_token_id` "));
+
+        let config = with("rope_scaling", r#"{"rope_type": "llama3", "factor": "big", "low_freq_factor": 1.0}"#);
+        assert!(error(&config).starts_with("Invalid config.json: `rope_scaling.factor` invalid type"));
+        let config = with("rope_scaling", r#"{"rope_type": ["llama3"]}"#);
+        assert!(error(&config).starts_with("Invalid config.json: `rope_scaling.rope_type` invalid type"));
+    }
+
+    #[test]
+    fn reads_defaults_for_null_fields() {
+        let config = ModelConfig::parse(&with("rope_theta", "null")).unwrap();
+        assert_eq!(config.rope_theta, 10_000.0);
+        assert!(ModelConfig::parse(&with("rope_scaling", "null")).unwrap().rope_scaling.is_none());
+    }
+
+    #[test]
+    fn rejects_configs_that_are_not_objects() {
+        assert_eq!(error("[1, 2]"), "Invalid config.json: expected a JSON object");
+        assert_eq!(error(&with("rope_scaling", "[]")), "Invalid config.json: `rope_scaling` must be an object");
+        assert!(error("{").starts_with("Invalid config.json: EOF"));
+    }
+
+    #[test]
+    fn validates_values() {
+        assert_eq!(error(&with("num_hidden_layers", "0")), "Invalid config.json: `num_hidden_layers` must be greater than 0");
+        assert!(error(&with("num_key_value_heads", "3")).starts_with("Invalid config.json: `num_key_value_heads` 3 does not divide"));
+        assert_eq!(error(&with("eos_token_id", "[]")), "Invalid config.json: `eos_token_id` must not be an empty list");
+        assert!(error(&with("bos_token_id", "100")).starts_with("Invalid config.json: `bos_token_id` 100 is outside"));
+    }
+
+    #[test]
+    fn runs_plain_llama_configs_as_mistral() {
+        let config = ModelConfig::parse(TINY).unwrap().to_mistral().unwrap();
+        assert_eq!(config.num_key_value_heads, 2);
+        assert_eq!(config.sliding_window, None);
+
+        let config = ModelConfig::parse(&with("rope_scaling", r#"{"rope_type": "default"}"#)).unwrap();
+        assert!(config.to_mistral().is_some());
+        let config = ModelConfig::parse(&with("rope_scaling", r#"{"rope_type": "llama3", "factor": 8.0}"#)).unwrap();
+        assert!(config.to_mistral().is_none());
+        let config = ModelConfig::parse(&with("tie_word_embeddings", "true")).unwrap();
+        assert!(config.to_mistral().is_none());
+    }
+
+    fn generation_config(json: &str) -> GenerationConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn generation_config_sets_sampling_defaults() {
+        let config = generation_config(r#"{"temperature": 0.6, "top_p": 0.9, "repetition_penalty": 1.3, "eos_token_id": [2, 7]}"#);
+        let params = config.sampling_defaults(SamplingParams::default());
+        assert_eq!((params.temperature, params.top_p, params.repetition_penalty), (0.6, 0.9, 1.3));
+        assert_eq!(params.top_k, SamplingParams::default().top_k);
+        assert_eq!(config.eos_token_ids(), vec![2, 7]);
+
+        let params = GenerationConfig::default().sampling_defaults(SamplingParams::default());
+        assert_eq!(params.temperature, SamplingParams::default().temperature);
+    }
+
+    #[test]
+    fn greedy_unless_sampling_is_switched_on() {
+        let params = generation_config(r#"{"do_sample": false, "temperature": 0.6}"#).sampling_defaults(SamplingParams::default());
+        assert_eq!(params.temperature, 0.0);
+        let params = generation_config(r#"{"do_sample": true, "temperature": 0.6}"#).sampling_defaults(SamplingParams::default());
+        assert_eq!(params.temperature, 0.6);
+    }
+
+    #[test]
+    fn checks_llama_rope_scaling() {
+        let config = ModelConfig::parse(&with("rope_scaling", r#"{"type": "linear", "factor": 2.0}"#)).unwrap();
+        let message = config.to_llama().unwrap_err().to_string();
+        assert!(message.contains("`rope_scaling.rope_type` 'linear' is not supported"), "{}", message);
+
+        let config = ModelConfig::parse(&with("rope_scaling", r#"{"rope_type": "llama3", "factor": 8.0}"#)).unwrap();
+        let message = config.to_llama().unwrap_err().to_string();
+        assert!(message.contains("`rope_scaling.low_freq_factor` is required"), "{}", message);
+    }
+}
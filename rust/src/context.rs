@@ -0,0 +1,141 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// What to do with a prompt that does not fit the model's context window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextOverflow {
+    /// Fail with a `ContextOverflowError`.
+    #[default]
+    Error,
+    /// Drop the oldest tokens, keeping a leading BOS token.
+    TruncateOldest,
+    /// Drop tokens from the middle, keeping the start (system prompt) and
+    /// the end (latest message) of the prompt.
+    TruncateMiddle,
+}
+
+/// The prompt is longer than the context window allows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextOverflowError {
+    pub prompt_tokens: usize,
+    /// Prompt tokens that fit once room for the response is reserved.
+    pub max_prompt_tokens: usize,
+    pub context_length: usize,
+}
+
+impl fmt::Display for ContextOverflowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Prompt is {} tokens but only {} fit in the {}-token context window",
+            self.prompt_tokens, self.max_prompt_tokens, self.context_length
+        )
+    }
+}
+
+impl std::error::Error for ContextOverflowError {}
+
+/// Number of prompt tokens allowed when `max_new_tokens` are to follow.
+/// At most half of the window is reserved for the response, so a large
+/// `max_new_tokens` does not reject every prompt.
+pub fn prompt_budget(context_length: usize, max_new_tokens: usize) -> usize {
+    context_length - max_new_tokens.min(context_length / 2)
+}
+
+/// Shortens `tokens` to at most `budget` tokens according to `strategy`.
+/// `bos_token_id` is kept in front when truncating the oldest tokens. A
+/// budget too small to keep any content is an error with every strategy.
+pub fn fit_to_context(
+    tokens: &[u32],
+    budget: usize,
+    context_length: usize,
+    bos_token_id: Option<u32>,
+    strategy: ContextOverflow,
+) -> Result<Vec<u32>, ContextOverflowError> {
+    if tokens.len() <= budget {
+        return Ok(tokens.to_vec());
+    }
+    let error = ContextOverflowError {
+        prompt_tokens: tokens.len(),
+        max_prompt_tokens: budget,
+        context_length,
+    };
+
+    match strategy {
+        ContextOverflow::Error => Err(error),
+        _ if budget == 0 => Err(error),
+        ContextOverflow::TruncateOldest => {
+            let head = usize::from(bos_token_id.is_some() && tokens.first() == bos_token_id.as_ref());
+            // Keeping nothing but BOS would leave no prompt to respond to
+            if budget <= head {
+                return Err(error);
+            }
+            let mut fitted = tokens[..head].to_vec();
+            fitted.extend_from_slice(&tokens[tokens.len() - (budget - head)..]);
+            Ok(fitted)
+        }
+        ContextOverflow::TruncateMiddle => {
+            let head = budget / 2;
+            let mut fitted = tokens[..head].to_vec();
+            fitted.extend_from_slice(&tokens[tokens.len() - (budget - head)..]);
+            Ok(fitted)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOS: u32 = 1;
+
+    fn fit(tokens: &[u32], budget: usize, strategy: ContextOverflow) -> Result<Vec<u32>, ContextOverflowError> {
+        fit_to_context(tokens, budget, 100, Some(BOS), strategy)
+    }
+
+    #[test]
+    fn budget_reserves_at_most_half_the_window() {
+        assert_eq!(prompt_budget(100, 30), 70);
+        assert_eq!(prompt_budget(100, 80), 50);
+        assert_eq!(prompt_budget(0, 10), 0);
+    }
+
+    #[test]
+    fn prompts_that_fit_are_kept() {
+        for strategy in [ContextOverflow::Error, ContextOverflow::TruncateOldest, ContextOverflow::TruncateMiddle] {
+            assert_eq!(fit(&[BOS, 5, 6], 3, strategy).unwrap(), vec![BOS, 5, 6]);
+        }
+    }
+
+    #[test]
+    fn error_strategy_reports_the_sizes() {
+        let error = fit(&[BOS, 5, 6, 7], 3, ContextOverflow::Error).unwrap_err();
+        assert_eq!(error, ContextOverflowError { prompt_tokens: 4, max_prompt_tokens: 3, context_length: 100 });
+    }
+
+    #[test]
+    fn truncate_oldest_keeps_bos() {
+        assert_eq!(fit(&[BOS, 10, 11, 12, 13], 3, ContextOverflow::TruncateOldest).unwrap(), vec![BOS, 12, 13]);
+        assert_eq!(fit(&[10, 11, 12, 13], 3, ContextOverflow::TruncateOldest).unwrap(), vec![11, 12, 13]);
+        let no_bos = fit_to_context(&[BOS, 10, 11, 12], 2, 100, None, ContextOverflow::TruncateOldest);
+        assert_eq!(no_bos.unwrap(), vec![11, 12]);
+    }
+
+    #[test]
+    fn truncate_middle_keeps_head_and_tail() {
+        let tokens: Vec<u32> = (1..=10).collect();
+        assert_eq!(fit(&tokens, 4, ContextOverflow::TruncateMiddle).unwrap(), vec![1, 2, 9, 10]);
+        assert_eq!(fit(&tokens, 5, ContextOverflow::TruncateMiddle).unwrap(), vec![1, 2, 8, 9, 10]);
+        assert_eq!(fit(&tokens, 1, ContextOverflow::TruncateMiddle).unwrap(), vec![10]);
+    }
+
+    #[test]
+    fn budgets_without_room_for_content_are_rejected() {
+        for strategy in [ContextOverflow::TruncateOldest, ContextOverflow::TruncateMiddle] {
+            assert!(fit(&[BOS, 10, 11], 0, strategy).is_err());
+        }
+        assert!(fit(&[BOS, 10, 11], 1, ContextOverflow::TruncateOldest).is_err());
+        assert_eq!(fit(&[10, 11], 1, ContextOverflow::TruncateOldest).unwrap(), vec![11]);
+    }
+}
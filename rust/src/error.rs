@@ -0,0 +1,155 @@
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::fmt;
+use std::os::raw::c_char;
+use crate::context::ContextOverflowError;
+
+pub type Result<T> = std::result::Result<T, LlmError>;
+
+/// Everything that can go wrong in the runner.
+#[derive(Debug)]
+pub enum LlmError {
+    /// A null pointer, invalid UTF-8 or malformed JSON was passed in.
+    InvalidArgument(String),
+    /// No model has been loaded yet.
+    NotLoaded,
+    /// Fetching a file from the Hugging Face Hub failed.
+    Download(String),
+    /// The Hub refused access, e.g. a gated or private repo.
+    Auth(String),
+    Io(String),
+    /// `config.json` or another model file is missing fields or describes
+    /// something we cannot run.
+    Config(String),
+    /// The weights could not be loaded.
+    Load(String),
+    Tokenizer(String),
+    /// The chat template failed to compile or render.
+    Template(String),
+    /// The model failed while generating.
+    Inference(String),
+    ContextOverflow(ContextOverflowError),
+    Cancelled,
+    /// A bug: something panicked and the panic was caught at the C boundary.
+    Panic(String),
+    /// A model id or file name that is not a valid Hugging Face identifier,
+    /// or that would resolve outside the storage root.
+    InvalidModelId(String),
+}
+
+/// Status code returned by the C API. The values are stable; new codes are
+/// only ever appended.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LlmStatus {
+    Ok = 0,
+    InvalidArgument = 1,
+    NotLoaded = 2,
+    Download = 3,
+    Auth = 4,
+    Io = 5,
+    Config = 6,
+    Load = 7,
+    Tokenizer = 8,
+    Template = 9,
+    Inference = 10,
+    ContextOverflow = 11,
+    Cancelled = 12,
+    Panic = 13,
+    InvalidModelId = 14,
+}
+
+impl LlmError {
+    pub fn status(&self) -> LlmStatus {
+        match self {
+            LlmError::InvalidArgument(_) => LlmStatus::InvalidArgument,
+            LlmError::NotLoaded => LlmStatus::NotLoaded,
+            LlmError::Download(_) => LlmStatus::Download,
+            LlmError::Auth(_) => LlmStatus::Auth,
+            LlmError::Io(_) => LlmStatus::Io,
+            LlmError::Config(_) => LlmStatus::Config,
+            LlmError::Load(_) => LlmStatus::Load,
+            LlmError::Tokenizer(_) => LlmStatus::Tokenizer,
+            LlmError::Template(_) => LlmStatus::Template,
+            LlmError::Inference(_) => LlmStatus::Inference,
+            LlmError::ContextOverflow(_) => LlmStatus::ContextOverflow,
+            LlmError::Cancelled => LlmStatus::Cancelled,
+            LlmError::Panic(_) => LlmStatus::Panic,
+            LlmError::InvalidModelId(_) => LlmStatus::InvalidModelId,
+        }
+    }
+
+    /// Whether a Hub download failed because the repo has no such file.
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, LlmError::Download(message) if message.contains("status code 404"))
+    }
+
+    /// Sorts a Hub error into `Auth` (401/403) or `Download`.
+    pub fn from_hub(context: &str, error: impl fmt::Display) -> Self {
+        let message = format!("{}: {}", context, error);
+        if message.contains("status code 401") || message.contains("status code 403") {
+            LlmError::Auth(message)
+        } else {
+            LlmError::Download(message)
+        }
+    }
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LlmError::InvalidArgument(msg) => write!(f, "Invalid argument: {}", msg),
+            LlmError::NotLoaded => write!(f, "Model not loaded"),
+            LlmError::Download(msg) => write!(f, "Download failed: {}", msg),
+            LlmError::Auth(msg) => write!(f, "Access denied: {}", msg),
+            LlmError::Io(msg) => write!(f, "I/O error: {}", msg),
+            LlmError::Config(msg) => write!(f, "Config error: {}", msg),
+            LlmError::Load(msg) => write!(f, "Failed to load model: {}", msg),
+            LlmError::Tokenizer(msg) => write!(f, "Tokenizer error: {}", msg),
+            LlmError::Template(msg) => write!(f, "Chat template error: {}", msg),
+            LlmError::Inference(msg) => write!(f, "Inference error: {}", msg),
+            LlmError::ContextOverflow(e) => write!(f, "{}", e),
+            LlmError::Cancelled => write!(f, "Generation cancelled"),
+            LlmError::Panic(msg) => write!(f, "Internal error: {}", msg),
+            LlmError::InvalidModelId(msg) => write!(f, "Invalid model id: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+/// candle errors surface while running the model; loading code maps its
+/// own to `Load`.
+impl From<candle_core::Error> for LlmError {
+    fn from(e: candle_core::Error) -> Self {
+        LlmError::Inference(e.to_string())
+    }
+}
+
+impl From<ContextOverflowError> for LlmError {
+    fn from(e: ContextOverflowError) -> Self {
+        LlmError::ContextOverflow(e)
+    }
+}
+
+thread_local! {
+    // Message of the last failed C API call on this thread
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Records `error` for `last_error_c` and returns its status code.
+pub fn set_last_error(error: &LlmError) -> LlmStatus {
+    let message = CString::new(error.to_string().replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    error.status()
+}
+
+pub fn clear_last_error() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
+
+/// Pointer to the last error message on this thread, or null. Valid until
+/// the next C API call on the same thread.
+pub fn last_error_ptr() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(std::ptr::null(), |msg| msg.as_ptr()))
+}
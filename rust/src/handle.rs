@@ -0,0 +1,133 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokenizers::Tokenizer;
+use crate::causal_lm::MemoryFootprint;
+use crate::inference::CancellationToken;
+use crate::lock;
+use crate::model::Model;
+use crate::vocab::SpecialTokens;
+
+/// What `model_info_c` reports about a loaded model.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelInfo {
+    pub name: String,
+    pub architecture: String,
+    pub context_length: usize,
+    /// Tokens currently held in the KV cache.
+    pub cached_tokens: usize,
+    pub weights_bytes: usize,
+    pub kv_cache_bytes: usize,
+    /// Estimated resident memory: weights plus KV cache.
+    pub memory_bytes: usize,
+}
+
+/// A loaded model as seen by the C API. Several can be alive at once, e.g.
+/// an embedding model next to a chat model; each has its own lock, KV cache
+/// and cancellation token.
+pub struct ModelHandle {
+    model: Mutex<Model>,
+    // Kept outside `model` so cancelling never waits on a running generation
+    generation: Mutex<Option<CancellationToken>>,
+    tokenizer: Arc<Tokenizer>,
+    special_tokens: SpecialTokens,
+    // What `info` reports, fixed at load apart from the cache length
+    name: String,
+    architecture: String,
+    context_length: usize,
+    memory: MemoryFootprint,
+    cache_len: Arc<AtomicUsize>,
+}
+
+impl ModelHandle {
+    pub fn new(model: Model) -> Self {
+        ModelHandle {
+            tokenizer: model.tokenizer.clone(),
+            special_tokens: model.special_tokens.clone(),
+            name: model.name.clone(),
+            architecture: format!("{:?}", model.architecture),
+            context_length: model.context_length,
+            memory: model.memory,
+            cache_len: model.shared_cache_len(),
+            model: Mutex::new(model),
+            generation: Mutex::new(None),
+        }
+    }
+
+    /// The model's tokenizer, usable while a generation is running.
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    /// The model's BOS/EOS/PAD/UNK ids, usable while a generation is running.
+    pub fn special_tokens(&self) -> &SpecialTokens {
+        &self.special_tokens
+    }
+
+    /// Name, size and estimated memory use of the model, usable while a
+    /// generation is running. The estimate covers the weights and the
+    /// current KV cache, not allocator overhead or scratch buffers.
+    pub fn info(&self) -> ModelInfo {
+        let cached_tokens = self.cache_len.load(Ordering::Relaxed);
+        let kv_cache_bytes = cached_tokens * self.memory.kv_cache_bytes_per_token;
+        ModelInfo {
+            name: self.name.clone(),
+            architecture: self.architecture.clone(),
+            context_length: self.context_length,
+            cached_tokens,
+            weights_bytes: self.memory.weights_bytes,
+            kv_cache_bytes,
+            memory_bytes: self.memory.weights_bytes + kv_cache_bytes,
+        }
+    }
+
+    /// Locks the model. After a panic the KV cache may be half-written, so
+    /// it is cleared before the model is used again.
+    pub fn lock(&self) -> MutexGuard<'_, Model> {
+        match self.model.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => {
+                self.model.clear_poison();
+                let mut guard = poisoned.into_inner();
+                if let Err(e) = guard.reset_cache() {
+                    println!("Failed to reset cache after a panic: {}", e);
+                }
+                guard
+            }
+        }
+    }
+
+    /// Registers a fresh cancellation token for the generation about to run.
+    /// Call it while holding the model lock so the token belongs to that run.
+    /// The token is unregistered when the returned guard is dropped.
+    pub fn begin_generation(&self) -> GenerationGuard<'_> {
+        let cancel = CancellationToken::new();
+        *lock(&self.generation) = Some(cancel.clone());
+        GenerationGuard { generation: &self.generation, cancel }
+    }
+
+    /// Asks the running generation to stop. Returns false if nothing was running.
+    pub fn cancel(&self) -> bool {
+        match &*lock(&self.generation) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A generation registered with `ModelHandle::begin_generation`. Dropping
+/// it, also while unwinding from a panic, tells `cancel` that nothing is
+/// running any more.
+pub struct GenerationGuard<'a> {
+    generation: &'a Mutex<Option<CancellationToken>>,
+    pub cancel: CancellationToken,
+}
+
+impl Drop for GenerationGuard<'_> {
+    fn drop(&mut self) {
+        *lock(self.generation) = None;
+    }
+}
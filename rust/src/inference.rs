@@ -1,82 +1,330 @@
-use candle_core::{Device, Tensor, Result};
-use std::io::{self, Write};
-use std::time::Instant;
-use crate::MODEL;  // Import the global MODEL from lib.rs
-
-pub fn run_inference(input: &str) -> Result<String> {
-    let start_time = Instant::now();
-    
-    // Get the model from global state
-    let model_lock = MODEL.lock().unwrap();
-    let model = model_lock.as_ref()
-        .ok_or_else(|| candle_core::Error::Msg("Model not loaded".to_string()))?;
-
-    println!("Got model from global state, running inference...");
-    
-    let prompt = format!("### Human: {}\n### Assistant:", input);
-    
-    let tokens = model.tokenizer.encode(prompt.as_str(), true)
-        .map_err(|e| candle_core::Error::Msg(format!("Tokenizer error: {:?}", e)))?;
-    
-    println!("Input text: {}", prompt);
-    println!("Input tokens: {:?}", tokens.get_ids());
-    
-    let token_ids = tokens.get_ids();
-    let input_tensor = Tensor::new(token_ids, &Device::Cpu)?
-        .reshape((1, token_ids.len()))?;
-    
-    println!("Input tensor shape: {:?}", input_tensor.shape());
-    println!("Generating response...");
-
-    let mut all_tokens = Vec::with_capacity(token_ids.len() + 50);
-    all_tokens.extend_from_slice(token_ids);
-    
-    let mut current_input = input_tensor;
-    let mut position = 0;
-    let mut total_tokens = 0;
-    let mut token_times = Vec::new();
-
-    for i in 0..50 {
-        let token_start = Instant::now();
-        
-        let output = model.model.forward(&current_input, position)?;
-        let logits = output.squeeze(0)?;
-        
-        let next_token_id = logits.argmax(0)?
-            .to_scalar::<u32>()?;
-        
-        if next_token_id == 2 {
-            break;
+use std::ffi::{c_void, CString};
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::error::{LlmError, LlmStatus};
+
+/// Lets another thread stop a running generation. The generation loop
+/// checks it before every step and returns the text produced so far.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Why a generation run ended.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The model produced a stop token.
+    #[default]
+    Stop = 0,
+    /// The token limit was reached.
+    Length = 1,
+    /// The run was cancelled; the output is partial.
+    Cancelled = 2,
+}
+
+/// Timing and token counts of one generation run.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GenerationStats {
+    pub prompt_tokens: u32,
+    pub generated_tokens: u32,
+    pub elapsed_ms: u64,
+    pub tokens_per_second: f64,
+    pub finish_reason: FinishReason,
+}
+
+impl GenerationStats {
+    pub fn new(
+        prompt_tokens: usize,
+        generated_tokens: usize,
+        elapsed: Duration,
+        finish_reason: FinishReason,
+    ) -> Self {
+        let secs = elapsed.as_secs_f64();
+        GenerationStats {
+            prompt_tokens: prompt_tokens as u32,
+            generated_tokens: generated_tokens as u32,
+            elapsed_ms: elapsed.as_millis() as u64,
+            tokens_per_second: if secs > 0.0 { generated_tokens as f64 / secs } else { 0.0 },
+            finish_reason,
+        }
+    }
+}
+
+/// Result of one generation run.
+#[derive(Debug, Clone)]
+pub struct GenerationOutput {
+    /// Decoded response text.
+    pub text: String,
+    /// Sampled token ids, not including the stop token.
+    pub tokens: Vec<u32>,
+    pub stats: GenerationStats,
+    /// The stop sequence that ended the run, if any. `text` ends before it,
+    /// but `tokens` still include the tokens that spelled it.
+    pub stop_sequence: Option<String>,
+}
+
+/// Response text being decoded, cut at the first stop sequence. Text is
+/// only released for streaming once it can no longer turn out to be the
+/// start of a stop sequence.
+pub struct StopSequenceFilter<'a> {
+    stops: &'a [String],
+    text: String,
+    /// Bytes of `text` released so far.
+    emitted: usize,
+    stop: Option<&'a str>,
+}
+
+impl<'a> StopSequenceFilter<'a> {
+    pub fn new(stops: &'a [String]) -> Self {
+        StopSequenceFilter { stops, text: String::new(), emitted: 0, stop: None }
+    }
+
+    /// Appends newly decoded text and returns what can be streamed now.
+    /// Nothing is added once a stop sequence was found.
+    pub fn push(&mut self, fragment: &str) -> String {
+        if self.stop.is_some() {
+            return String::new();
+        }
+        self.text.push_str(fragment);
+        let end = match find_stop_sequence(&self.text, self.stops) {
+            Some((pos, stop)) => {
+                self.text.truncate(pos);
+                self.stop = Some(stop);
+                pos
+            }
+            // Hold back anything that may turn out to be a stop sequence
+            None => self.text.len() - partial_stop_len(&self.text, self.stops),
+        };
+        let released = self.text.get(self.emitted..end).unwrap_or_default().to_string();
+        self.emitted = self.emitted.max(end);
+        released
+    }
+
+    /// The stop sequence that ended the text, if one was found.
+    pub fn stop_sequence(&self) -> Option<&'a str> {
+        self.stop
+    }
+
+    /// Appends the decoder's last text and releases everything held back.
+    /// Returns the text still to stream, the full response and the stop
+    /// sequence that ended it.
+    pub fn finish(mut self, tail: &str) -> (String, String, Option<String>) {
+        let mut rest = self.push(tail);
+        if self.stop.is_none() {
+            rest.push_str(self.text.get(self.emitted..).unwrap_or_default());
         }
-        
-        all_tokens.push(next_token_id);
-        total_tokens += 1;
-        
-        let token_time = token_start.elapsed().as_millis();
-        token_times.push(token_time);
-        
-        let avg_time = token_times.iter().sum::<u128>() / token_times.len() as u128;
-        print!("\rToken {}/50 ({}ms/token avg)", i + 1, avg_time);
-        io::stdout().flush().unwrap();
-        
-        current_input = Tensor::new(&[next_token_id], &Device::Cpu)?
-            .reshape((1, 1))?;
-        position += 1;
-        
-        if all_tokens.len() > token_ids.len() + 10 && next_token_id == 13 {
-            break;
+        (rest, self.text, self.stop.map(str::to_string))
+    }
+}
+
+/// Returns the byte offset and text of the earliest stop sequence in `text`.
+fn find_stop_sequence<'a>(text: &str, stops: &'a [String]) -> Option<(usize, &'a str)> {
+    stops
+        .iter()
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| text.find(stop.as_str()).map(|pos| (pos, stop.as_str())))
+        .min_by_key(|(pos, _)| *pos)
+}
+
+/// Length in bytes of the longest suffix of `text` that could be the start
+/// of a stop sequence. Streaming holds that much back until it is decided.
+fn partial_stop_len(text: &str, stops: &[String]) -> usize {
+    stops
+        .iter()
+        .flat_map(|stop| stop.char_indices().skip(1).map(move |(end, _)| &stop[..end]))
+        .filter(|prefix| text.ends_with(prefix))
+        .map(str::len)
+        .max()
+        .unwrap_or(0)
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamEventKind {
+    /// `text` holds the next fragment of the response.
+    Token = 0,
+    /// Generation finished; `text` holds the full response (partial if
+    /// cancelled) and `stats` is filled in.
+    Done = 1,
+    /// Generation failed; `text` holds the error message.
+    Error = 2,
+}
+
+/// Event passed to a `StreamCallback`. `text` is only valid for the duration
+/// of the callback and must be copied if it is needed afterwards.
+#[repr(C)]
+pub struct StreamEvent {
+    pub kind: StreamEventKind,
+    /// `Ok` except for `Error` events and the `Done` event of a cancelled run.
+    pub status: LlmStatus,
+    pub text: *const c_char,
+    pub stats: GenerationStats,
+}
+
+pub type StreamCallback = extern "C" fn(event: *const StreamEvent, user_data: *mut c_void);
+
+/// Forwards generation events to a C callback.
+pub struct StreamSink {
+    callback: StreamCallback,
+    user_data: *mut c_void,
+}
+
+impl StreamSink {
+    pub fn new(callback: StreamCallback, user_data: *mut c_void) -> Self {
+        StreamSink { callback, user_data }
+    }
+
+    pub fn token(&self, text: &str) {
+        self.send(StreamEventKind::Token, LlmStatus::Ok, text, GenerationStats::default());
+    }
+
+    pub fn done(&self, text: &str, stats: GenerationStats) {
+        let status = match stats.finish_reason {
+            FinishReason::Cancelled => LlmStatus::Cancelled,
+            _ => LlmStatus::Ok,
+        };
+        self.send(StreamEventKind::Done, status, text, stats);
+    }
+
+    pub fn error(&self, error: &LlmError) {
+        self.send(StreamEventKind::Error, error.status(), &error.to_string(), GenerationStats::default());
+    }
+
+    fn send(&self, kind: StreamEventKind, status: LlmStatus, text: &str, stats: GenerationStats) {
+        // Interior NULs would truncate the fragment on the C side anyway
+        let text = CString::new(text.replace('\0', "")).unwrap_or_default();
+        let event = StreamEvent { kind, status, text: text.as_ptr(), stats };
+        (self.callback)(&event, self.user_data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owned(stops: &[&str]) -> Vec<String> {
+        stops.iter().map(|stop| stop.to_string()).collect()
+    }
+
+    /// Feeds `fragments` through a filter and returns what was streamed
+    /// per fragment, the final text and the stop sequence.
+    fn run(stops: &[String], fragments: &[&str]) -> (Vec<String>, String, Option<String>) {
+        let mut filter = StopSequenceFilter::new(stops);
+        let mut streamed = Vec::new();
+        for fragment in fragments {
+            streamed.push(filter.push(fragment));
+            if filter.stop_sequence().is_some() {
+                break;
+            }
         }
+        let (rest, text, stop) = filter.finish("");
+        streamed.push(rest);
+        (streamed, text, stop)
+    }
+
+    #[test]
+    fn finds_the_earliest_stop_sequence() {
+        let stops = owned(&["User", "\nUser:", ""]);
+        assert_eq!(find_stop_sequence("hi\nUser: there", &stops), Some((2, "\nUser:")));
+        assert_eq!(find_stop_sequence("Username", &stops), Some((0, "User")));
+        assert_eq!(find_stop_sequence("nothing", &stops), None);
+    }
+
+    #[test]
+    fn measures_partial_stop_sequences() {
+        let stops = owned(&["</s>", "abc", "bcd"]);
+        assert_eq!(partial_stop_len("hello </", &stops), 2);
+        assert_eq!(partial_stop_len("xab", &stops), 2);
+        assert_eq!(partial_stop_len("xbc", &stops), 2);
+        // A complete stop sequence is not a partial one
+        assert_eq!(partial_stop_len("x</s>", &stops), 0);
+        assert_eq!(partial_stop_len("é", &owned(&["éa"])), "é".len());
+    }
+
+    #[test]
+    fn matches_a_stop_sequence_split_across_tokens() {
+        let (streamed, text, stop) = run(&owned(&["<|end|>"]), &["Hi", " there<", "|en", "d|", ">more"]);
+        assert_eq!(streamed, vec!["Hi", " there", "", "", "", ""]);
+        assert_eq!(text, "Hi there");
+        assert_eq!(stop.as_deref(), Some("<|end|>"));
+    }
+
+    #[test]
+    fn releases_held_back_text_that_is_not_a_stop_sequence() {
+        let (streamed, text, stop) = run(&owned(&["<|end|>"]), &["a <|", "en", "t", "ry"]);
+        assert_eq!(streamed, vec!["a ", "", "<|ent", "ry", ""]);
+        assert_eq!(text, "a <|entry");
+        assert_eq!(stop, None);
+
+        // Held back at the end of the run and released by `finish`
+        let (streamed, text, _) = run(&owned(&["<|end|>"]), &["a <|en"]);
+        assert_eq!(streamed, vec!["a ", "<|en"]);
+        assert_eq!(text, "a <|en");
     }
 
-    let final_text = model.tokenizer.decode(&all_tokens[token_ids.len()..], true)
-        .map_err(|e| candle_core::Error::Msg(format!("Decoding error: {:?}", e)))?;
+    #[test]
+    fn trims_the_stop_sequence_from_streamed_and_final_text() {
+        let (streamed, text, stop) = run(&owned(&["\nUser:"]), &["Sure.", "\nUs", "er: next"]);
+        assert_eq!(streamed.concat(), text);
+        assert_eq!(text, "Sure.");
+        assert_eq!(stop.as_deref(), Some("\nUser:"));
 
-    let total_time = start_time.elapsed().as_millis();
-    println!("\n\nStats:");
-    println!("Total time: {}ms", total_time);
-    println!("Total tokens generated: {}", total_tokens);
-    println!("Average time per token: {}ms", total_time / total_tokens as u128);
-    println!("Tokens per second: {:.2}", (total_tokens as f64 * 1000.0) / total_time as f64);
+        // Completed only by the decoder's last text
+        let stops = owned(&["</s>"]);
+        let mut filter = StopSequenceFilter::new(&stops);
+        assert_eq!(filter.push("done</"), "done");
+        let (rest, text, stop) = filter.finish("s>");
+        assert_eq!((rest.as_str(), text.as_str(), stop.as_deref()), ("", "done", Some("</s>")));
+    }
 
-    Ok(final_text)
+    #[test]
+    fn stops_at_the_earliest_of_several_stop_sequences() {
+        // "abc" and "bcd" overlap; "abc" starts first, though "bcd" is listed first
+        let (streamed, text, stop) = run(&owned(&["bcd", "abc"]), &["xa", "b", "cd"]);
+        assert_eq!(streamed, vec!["x", "", "", ""]);
+        assert_eq!(text, "x");
+        assert_eq!(stop.as_deref(), Some("abc"));
+
+        let (streamed, text, stop) = run(&owned(&["STOP", "\n\n"]), &["a\n", "\nSTOP"]);
+        assert_eq!(streamed.concat(), "a");
+        assert_eq!(text, "a");
+        assert_eq!(stop.as_deref(), Some("\n\n"));
+    }
+
+    extern "C" fn record(event: *const StreamEvent, user_data: *mut c_void) {
+        let event = unsafe { &*event };
+        let text = unsafe { std::ffi::CStr::from_ptr(event.text) }.to_string_lossy().into_owned();
+        let events = unsafe { &mut *(user_data as *mut Vec<(StreamEventKind, LlmStatus, String)>) };
+        events.push((event.kind, event.status, text));
+    }
+
+    #[test]
+    fn a_cancelled_run_ends_with_its_partial_text_and_status() {
+        let mut events: Vec<(StreamEventKind, LlmStatus, String)> = Vec::new();
+        let sink = StreamSink::new(record, &mut events as *mut _ as *mut c_void);
+        sink.token("Hel");
+        sink.done("Hel", GenerationStats::new(3, 1, Duration::ZERO, FinishReason::Cancelled));
+        sink.done("Hello", GenerationStats::new(3, 2, Duration::ZERO, FinishReason::Stop));
+        assert_eq!(events, vec![
+            (StreamEventKind::Token, LlmStatus::Ok, "Hel".to_string()),
+            (StreamEventKind::Done, LlmStatus::Cancelled, "Hel".to_string()),
+            (StreamEventKind::Done, LlmStatus::Ok, "Hello".to_string()),
+        ]);
+    }
 }
@@ -2,134 +2,954 @@ mod model;
 mod inference;
 mod downloader;
 mod tokenizer;
+mod sentencepiece;
+mod sampler;
+mod session;
+mod chat_template;
+mod causal_lm;
+mod config;
+mod context;
+mod error;
+mod handle;
+mod storage;
+mod vocab;
 
-use std::ffi::{CStr, CString};
+use std::ffi::{c_void, CStr, CString};
 use std::os::raw::c_char;
-use std::sync::Once;
-use std::sync::Mutex;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard};
 use lazy_static::lazy_static;
 use model::Model;
-use inference::run_inference;
-use downloader::download_if_needed;
-use std::error::Error;
-use std::path::{Path, PathBuf};
+use sampler::SamplingParams;
+use inference::{FinishReason, StreamEvent, StreamSink};
+use handle::ModelHandle;
+use error::{clear_last_error, set_last_error, LlmError, LlmStatus, Result};
+use session::{ChatSession, Role};
 
-// Global model instance
 lazy_static! {
-    static ref MODEL: Mutex<Option<Model>> = Mutex::new(None);
+    // Model used by the functions that take no handle
+    static ref MODEL: Mutex<Option<Arc<ModelHandle>>> = Mutex::new(None);
 }
-static INIT: Once = Once::new();
 
+/// The model loaded by `load_model_c` or `load_gguf_model_c`.
+fn default_model() -> Result<Arc<ModelHandle>> {
+    lock(&MODEL).clone().ok_or(LlmError::NotLoaded)
+}
+
+/// Locks `mutex`, taking the data back if a panic poisoned it. The panic was
+/// already reported as `LlmStatus::Panic`; later calls should keep working.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// Sets the directory models are downloaded to and loaded from, e.g. the
+/// app's documents directory on iOS/Android; call it before anything else.
+/// Models go to `<root>/models/<org>/<name>` and the Hub's download cache to
+/// `<root>/hub-cache`. Pass null to fall back to `$LLM_RUNNER_HOME`, or to
+/// `models` in the working directory if that is unset.
+#[no_mangle]
+pub extern "C" fn set_storage_root_c(path: *const c_char) -> LlmStatus {
+    ffi_status(|| {
+        let path_str = optional_str_from_c(path, "path")?;
+        storage::set_root(path_str.map(std::path::Path::new))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn download_model_c(model_name: *const c_char) -> LlmStatus {
+    ffi_status(|| {
+        let model_str = str_from_c(model_name, "model name")?;
+        println!("Downloading model if needed: {}", model_str);
+        Model::download_if_needed(model_str)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn load_model_c(model_name: *const c_char) -> LlmStatus {
+    ffi_status(|| {
+        let model_str = str_from_c(model_name, "model name")?;
+        println!("Loading model: {}", model_str);
+        let model = Model::load_from_hub(model_str)?;
+        *lock(&MODEL) = Some(Arc::new(ModelHandle::new(model)));
+        Ok(())
+    })
+}
+
+/// Downloads a quantized GGUF file, e.g. `TheBloke/TinyLlama-1.1B-Chat-v1.0-GGUF`
+/// and `tinyllama-1.1b-chat-v1.0.Q4_K_M.gguf`. The tokenizer files come from
+/// `tokenizer_repo`, or from `repo_id` when it is null.
+#[no_mangle]
+pub extern "C" fn download_gguf_model_c(
+    repo_id: *const c_char,
+    filename: *const c_char,
+    tokenizer_repo: *const c_char,
+) -> LlmStatus {
+    ffi_status(|| {
+        let repo_str = str_from_c(repo_id, "repo id")?;
+        let filename_str = str_from_c(filename, "filename")?;
+        let tokenizer_repo_str = optional_str_from_c(tokenizer_repo, "tokenizer repo")?;
+        println!("Downloading GGUF model if needed: {}/{}", repo_str, filename_str);
+        Model::download_gguf_if_needed(repo_str, filename_str, tokenizer_repo_str)
+    })
+}
+
+/// Like `load_model_c`, for a quantized GGUF file. Arguments are the same as
+/// for `download_gguf_model_c`.
+#[no_mangle]
+pub extern "C" fn load_gguf_model_c(
+    repo_id: *const c_char,
+    filename: *const c_char,
+    tokenizer_repo: *const c_char,
+) -> LlmStatus {
+    ffi_status(|| {
+        let model = load_gguf(repo_id, filename, tokenizer_repo)?;
+        *lock(&MODEL) = Some(Arc::new(ModelHandle::new(model)));
+        Ok(())
+    })
+}
+
+/// Drops the loaded model, freeing its weights, memory maps and KV cache,
+/// e.g. when the app goes to the background. A running generation is
+/// cancelled; its memory is released once that call returns. Chat sessions
+/// stay valid and replay their history on the next load. Succeeds if no
+/// model was loaded.
 #[no_mangle]
-pub extern "C" fn download_model_c(model_name: *const c_char) -> *mut c_char {
-    let model_str = unsafe { 
-        if model_name.is_null() {
-            return CString::new("Model name is null").unwrap().into_raw();
+pub extern "C" fn unload_model_c() -> LlmStatus {
+    ffi_status(|| {
+        if let Some(handle) = lock(&MODEL).take() {
+            println!("Unloading model");
+            handle.cancel();
         }
-        match CStr::from_ptr(model_name).to_str() {
-            Ok(s) => s,
-            Err(_) => return CString::new("Invalid model name string").unwrap().into_raw(),
+        Ok(())
+    })
+}
+
+/// Runs the prompt with the model's default settings and stores the
+/// response in `*out` (free it with `free_string_c`). If the run was
+/// cancelled the status is `Cancelled` and `*out` holds the partial response.
+#[no_mangle]
+pub extern "C" fn run_inference_c(input: *const c_char, out: *mut *mut c_char) -> LlmStatus {
+    run_inference_with_params_c(input, std::ptr::null(), out)
+}
+
+/// Like `run_inference_c`, but with sampling settings passed as a JSON object,
+/// e.g. `{"temperature": 0.7, "top_k": 40, "top_p": 0.9, "min_p": 0.05, "seed": 42,
+/// "repetition_penalty": 1.1, "frequency_penalty": 0.2, "presence_penalty": 0.1,
+/// "penalty_last_n": 64, "max_new_tokens": 256, "stop": ["\nUser:"],
+/// "context_overflow": "truncate_middle"}`. `context_overflow` is "error" (default),
+/// "truncate_oldest" or "truncate_middle".
+/// Missing fields fall back to the model's defaults (its `generation_config.json`,
+/// then the built-in ones); a null `params_json` uses all defaults.
+#[no_mangle]
+pub extern "C" fn run_inference_with_params_c(
+    input: *const c_char,
+    params_json: *const c_char,
+    out: *mut *mut c_char,
+) -> LlmStatus {
+    ffi_status(|| run_inference(default_model(), input, params_json, out))
+}
+
+/// Streams the response through `callback`: one `Token` event per decoded
+/// text fragment, then a single `Done` event carrying the full text and
+/// stats, or an `Error` event with its status code. A cancelled run ends
+/// with a `Done` event holding the partial text, `FinishReason::Cancelled`
+/// and status `Cancelled`. The callback runs on the calling thread before
+/// this function returns, which then returns the same status.
+/// `params_json` is the same as for `run_inference_with_params_c` and may be null.
+#[no_mangle]
+pub extern "C" fn run_inference_stream_c(
+    input: *const c_char,
+    params_json: *const c_char,
+    callback: Option<extern "C" fn(event: *const StreamEvent, user_data: *mut c_void)>,
+    user_data: *mut c_void,
+) -> LlmStatus {
+    ffi_status(|| run_inference_stream(default_model(), input, params_json, callback, user_data))
+}
+
+/// Clears the KV cache of the loaded model. Regular inference calls already
+/// start from an empty cache; this frees the cached keys/values by hand.
+#[no_mangle]
+pub extern "C" fn reset_cache_c() -> LlmStatus {
+    ffi_status(|| default_model()?.lock().reset_cache())
+}
+
+/// Stores a JSON description of the loaded model in `*out` (free it with
+/// `free_string_c`): `{"name", "architecture", "context_length",
+/// "cached_tokens", "weights_bytes", "kv_cache_bytes", "memory_bytes"}`.
+/// `memory_bytes` estimates the resident memory of weights plus KV cache.
+/// Does not wait for a running generation.
+#[no_mangle]
+pub extern "C" fn model_info_c(out: *mut *mut c_char) -> LlmStatus {
+    ffi_status(|| model_info(default_model(), out))
+}
+
+/// Overrides the chat template used for `model_name`, now and on future
+/// loads. `template` is Jinja source in Hugging Face's `chat_template`
+/// format; pass null to go back to the model's own template.
+#[no_mangle]
+pub extern "C" fn set_chat_template_c(model_name: *const c_char, template: *const c_char) -> LlmStatus {
+    ffi_status(|| {
+        let model_str = str_from_c(model_name, "model name")?;
+        let template_str = optional_str_from_c(template, "template")?;
+
+        if let Ok(handle) = default_model() {
+            let mut model = handle.lock();
+            if model.name == model_str {
+                model.set_chat_template(template_str)?;
+            }
         }
-    };
-    
-    println!("Downloading model if needed: {}", model_str);
-    
-    match Model::download_if_needed(model_str) {
-        Ok(_) => CString::new("Download complete").unwrap().into_raw(),
-        Err(e) => CString::new(format!("Download failed: {:?}", e)).unwrap().into_raw(),
-    }
+        chat_template::set_override(model_str, template_str);
+        Ok(())
+    })
 }
 
+/// Asks the running generation to stop. Safe to call from any thread; the
+/// interrupted call returns (or streams as `Done`) the text generated so far.
+/// Returns false if nothing was running.
 #[no_mangle]
-pub extern "C" fn load_model_c(model_name: *const c_char) -> *mut c_char {
-    let model_str = unsafe { 
-        if model_name.is_null() {
-            return CString::new("Model name is null").unwrap().into_raw();
+pub extern "C" fn cancel_inference_c() -> bool {
+    default_model().is_ok_and(|handle| handle.cancel())
+}
+
+/// Starts a new chat session. `system_prompt` may be null. The session must
+/// be released with `chat_session_destroy_c`. Returns null on failure.
+#[no_mangle]
+pub extern "C" fn chat_session_create_c(system_prompt: *const c_char) -> *mut ChatSession {
+    ffi_ptr(|| {
+        let system_prompt = optional_str_from_c(system_prompt, "system prompt")?;
+        Ok(Box::into_raw(Box::new(ChatSession::new(system_prompt))))
+    })
+}
+
+/// Adds a message to the session. `role` is "system", "user" or "assistant".
+// `session` must come from `chat_session_create_c`; only null is caught
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn chat_session_append_c(
+    session: *mut ChatSession,
+    role: *const c_char,
+    content: *const c_char,
+) -> LlmStatus {
+    ffi_status(|| {
+        if session.is_null() {
+            return Err(LlmError::InvalidArgument("session is null".to_string()));
         }
-        match CStr::from_ptr(model_name).to_str() {
-            Ok(s) => s,
-            Err(_) => return CString::new("Invalid model name string").unwrap().into_raw(),
+        let session = unsafe { &mut *session };
+        let role = str_from_c(role, "role")?.parse::<Role>()?;
+        session.append(role, str_from_c(content, "content")?);
+        Ok(())
+    })
+}
+
+/// Generates the assistant's reply to the session's pending messages, stores
+/// it in `*out` (free it with `free_string_c`) and records it in the
+/// conversation. Only the new turn is prefilled while the model's KV cache
+/// still holds this session. `params_json` may be null.
+#[no_mangle]
+pub extern "C" fn chat_session_generate_c(
+    session: *mut ChatSession,
+    params_json: *const c_char,
+    out: *mut *mut c_char,
+) -> LlmStatus {
+    ffi_status(|| chat_session_generate(default_model(), session, params_json, out))
+}
+
+// `session` must come from `chat_session_create_c`; only null is caught
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+#[no_mangle]
+pub extern "C" fn chat_session_destroy_c(session: *mut ChatSession) {
+    ffi_status(|| {
+        if !session.is_null() {
+            unsafe { drop(Box::from_raw(session)) };
         }
-    };
-    
-    println!("Loading model: {}", model_str);
-    
-    match Model::load_from_hub(model_str) {
-        Ok(model) => {
-            let mut model_ref = MODEL.lock().unwrap();
-            *model_ref = Some(model);
-            CString::new("Model loaded successfully").unwrap().into_raw()
-        },
-        Err(e) => {
-            CString::new(format!("Failed to load model: {}", e)).unwrap().into_raw()
+        Ok(())
+    });
+}
+
+/// Loads a model from the Hugging Face Hub, like `load_model_c`, but
+/// returns it as a handle instead of replacing the global model. Any number
+/// of models can be loaded this way; release each with `llm_model_free`.
+/// Returns null on failure.
+#[no_mangle]
+pub extern "C" fn llm_model_load(model_name: *const c_char) -> *mut ModelHandle {
+    ffi_ptr(|| {
+        let model_str = str_from_c(model_name, "model name")?;
+        println!("Loading model: {}", model_str);
+        let model = Model::load_from_hub(model_str)?;
+        Ok(Arc::into_raw(Arc::new(ModelHandle::new(model))) as *mut ModelHandle)
+    })
+}
+
+/// Like `llm_model_load`, for a quantized GGUF file. Arguments are the same
+/// as for `download_gguf_model_c`.
+#[no_mangle]
+pub extern "C" fn llm_model_load_gguf(
+    repo_id: *const c_char,
+    filename: *const c_char,
+    tokenizer_repo: *const c_char,
+) -> *mut ModelHandle {
+    ffi_ptr(|| {
+        let model = load_gguf(repo_id, filename, tokenizer_repo)?;
+        Ok(Arc::into_raw(Arc::new(ModelHandle::new(model))) as *mut ModelHandle)
+    })
+}
+
+/// Releases a handle from `llm_model_load`. No call on it may be running or
+/// start afterwards. Null is ignored.
+#[no_mangle]
+pub extern "C" fn llm_model_free(model: *mut ModelHandle) {
+    ffi_status(|| {
+        if !model.is_null() {
+            unsafe { drop(Arc::from_raw(model as *const ModelHandle)) };
         }
-    }
+        Ok(())
+    });
+}
+
+/// `run_inference_with_params_c` on the given model.
+#[no_mangle]
+pub extern "C" fn llm_model_run_inference(
+    model: *mut ModelHandle,
+    input: *const c_char,
+    params_json: *const c_char,
+    out: *mut *mut c_char,
+) -> LlmStatus {
+    ffi_status(|| run_inference(handle_from_c(model), input, params_json, out))
+}
+
+/// `run_inference_stream_c` on the given model.
+#[no_mangle]
+pub extern "C" fn llm_model_run_inference_stream(
+    model: *mut ModelHandle,
+    input: *const c_char,
+    params_json: *const c_char,
+    callback: Option<extern "C" fn(event: *const StreamEvent, user_data: *mut c_void)>,
+    user_data: *mut c_void,
+) -> LlmStatus {
+    ffi_status(|| run_inference_stream(handle_from_c(model), input, params_json, callback, user_data))
+}
+
+/// `reset_cache_c` on the given model.
+#[no_mangle]
+pub extern "C" fn llm_model_reset_cache(model: *mut ModelHandle) -> LlmStatus {
+    ffi_status(|| handle_from_c(model)?.lock().reset_cache())
 }
 
+/// `model_info_c` for the given model.
 #[no_mangle]
-pub extern "C" fn run_inference_c(input: *const c_char) -> *mut c_char {
-    let input_str = unsafe {
-        if input.is_null() {
-            return CString::new("Input is null").unwrap().into_raw();
+pub extern "C" fn llm_model_info(model: *mut ModelHandle, out: *mut *mut c_char) -> LlmStatus {
+    ffi_status(|| model_info(handle_from_c(model), out))
+}
+
+/// Replaces the chat template of this model only; pass null to go back to
+/// the model's own template. Use `set_chat_template_c` to also apply it to
+/// future loads.
+#[no_mangle]
+pub extern "C" fn llm_model_set_chat_template(model: *mut ModelHandle, template: *const c_char) -> LlmStatus {
+    ffi_status(|| {
+        let template_str = optional_str_from_c(template, "template")?;
+        handle_from_c(model)?.lock().set_chat_template(template_str)
+    })
+}
+
+/// `cancel_inference_c` for the given model.
+#[no_mangle]
+pub extern "C" fn llm_model_cancel(model: *mut ModelHandle) -> bool {
+    handle_from_c(model).is_ok_and(|handle| handle.cancel())
+}
+
+/// `chat_session_generate_c` on the given model. A session may move between
+/// models; its history is replayed on the first turn with a new one.
+#[no_mangle]
+pub extern "C" fn llm_model_chat_session_generate(
+    model: *mut ModelHandle,
+    session: *mut ChatSession,
+    params_json: *const c_char,
+    out: *mut *mut c_char,
+) -> LlmStatus {
+    ffi_status(|| chat_session_generate(handle_from_c(model), session, params_json, out))
+}
+
+fn load_gguf(repo_id: *const c_char, filename: *const c_char, tokenizer_repo: *const c_char) -> Result<Model> {
+    let repo_str = str_from_c(repo_id, "repo id")?;
+    let filename_str = str_from_c(filename, "filename")?;
+    let tokenizer_repo_str = optional_str_from_c(tokenizer_repo, "tokenizer repo")?;
+    println!("Loading GGUF model: {}/{}", repo_str, filename_str);
+    Model::load_gguf_from_hub(repo_str, filename_str, tokenizer_repo_str)
+}
+
+/// Shared body of the inference calls. `model` is only checked after the
+/// arguments, so a missing model never hides a bad argument.
+fn run_inference(
+    model: Result<Arc<ModelHandle>>,
+    input: *const c_char,
+    params_json: *const c_char,
+    out: *mut *mut c_char,
+) -> Result<()> {
+    clear_out(out)?;
+    let input_str = str_from_c(input, "input")?;
+
+    let handle = model?;
+    let mut model = handle.lock();
+    let params = sampling_params_from_c(params_json, &model.default_params)?;
+    println!("Running inference with {:?}", params);
+
+    let generation = handle.begin_generation();
+    let output = model.generate(input_str, &params, &generation.cancel, &mut |_| {})?;
+    write_out(out, &output.text);
+    match output.stats.finish_reason {
+        FinishReason::Cancelled => Err(LlmError::Cancelled),
+        _ => Ok(()),
+    }
+}
+
+fn run_inference_stream(
+    model: Result<Arc<ModelHandle>>,
+    input: *const c_char,
+    params_json: *const c_char,
+    callback: Option<extern "C" fn(event: *const StreamEvent, user_data: *mut c_void)>,
+    user_data: *mut c_void,
+) -> Result<()> {
+    let callback = callback.ok_or_else(|| LlmError::InvalidArgument("callback is null".to_string()))?;
+    let sink = StreamSink::new(callback, user_data);
+
+    let result = catch_panic(|| {
+        let input_str = str_from_c(input, "input")?;
+        let handle = model?;
+        let mut model = handle.lock();
+        let params = sampling_params_from_c(params_json, &model.default_params)?;
+
+        let generation = handle.begin_generation();
+        model.generate(input_str, &params, &generation.cancel, &mut |text| sink.token(text))
+    });
+
+    match result {
+        Ok(output) => {
+            sink.done(&output.text, output.stats);
+            match output.stats.finish_reason {
+                FinishReason::Cancelled => Err(LlmError::Cancelled),
+                _ => Ok(()),
+            }
         }
-        match CStr::from_ptr(input).to_str() {
-            Ok(s) => s,
-            Err(_) => return CString::new("Invalid input string").unwrap().into_raw(),
+        Err(e) => {
+            sink.error(&e);
+            Err(e)
         }
-    };
+    }
+}
 
-    let model_ref = MODEL.lock().unwrap();
-    match &*model_ref {
-        Some(model) => {
-            println!("Got model from global state, running inference...");
-            println!("Input text: {}", input_str);
-            
-            match model.run_inference(input_str) {
-                Ok(output) => CString::new(output).unwrap().into_raw(),
-                Err(e) => CString::new(format!("Inference error: {}", e)).unwrap().into_raw(),
-            }
+fn chat_session_generate(
+    model: Result<Arc<ModelHandle>>,
+    session: *mut ChatSession,
+    params_json: *const c_char,
+    out: *mut *mut c_char,
+) -> Result<()> {
+    clear_out(out)?;
+    if session.is_null() {
+        return Err(LlmError::InvalidArgument("session is null".to_string()));
+    }
+    let session = unsafe { &mut *session };
+
+    let handle = model?;
+    let mut model = handle.lock();
+    let params = sampling_params_from_c(params_json, &model.default_params)?;
+
+    let generation = handle.begin_generation();
+    let output = session.generate(&mut model, &params, &generation.cancel, &mut |_| {})?;
+    write_out(out, &output.text);
+    match output.stats.finish_reason {
+        FinishReason::Cancelled => Err(LlmError::Cancelled),
+        _ => Ok(()),
+    }
+}
+
+fn model_info(model: Result<Arc<ModelHandle>>, out: *mut *mut c_char) -> Result<()> {
+    clear_out(out)?;
+    let info = model?.info();
+    let json = serde_json::to_string(&info)
+        .map_err(|e| LlmError::Inference(format!("Failed to serialize model info: {}", e)))?;
+    write_out(out, &json);
+    Ok(())
+}
+
+/// Borrows a handle from `llm_model_load` without taking it over.
+fn handle_from_c(model: *mut ModelHandle) -> Result<Arc<ModelHandle>> {
+    if model.is_null() {
+        return Err(LlmError::InvalidArgument("model is null".to_string()));
+    }
+    let model = model as *const ModelHandle;
+    unsafe {
+        Arc::increment_strong_count(model);
+        Ok(Arc::from_raw(model))
+    }
+}
+
+/// Message describing the last failed call on this thread, or null if it
+/// succeeded. The string is owned by the library and stays valid until the
+/// next call on the same thread; do not free it.
+#[no_mangle]
+pub extern "C" fn last_error_c() -> *const c_char {
+    error::last_error_ptr()
+}
+
+/// Runs the body of an exported function and turns its result into a
+/// status code, recording the error message for `last_error_c`. A panic
+/// becomes `LlmStatus::Panic` instead of unwinding into the host app.
+fn ffi_status(body: impl FnOnce() -> Result<()>) -> LlmStatus {
+    clear_last_error();
+    match catch_panic(body) {
+        Ok(()) => LlmStatus::Ok,
+        Err(e) => set_last_error(&e),
+    }
+}
+
+/// Like `ffi_status`, for exported functions that return a pointer; null
+/// means failure and `last_error_c` says why.
+pub(crate) fn ffi_ptr<T>(body: impl FnOnce() -> Result<*mut T>) -> *mut T {
+    clear_last_error();
+    match catch_panic(body) {
+        Ok(ptr) => ptr,
+        Err(e) => {
+            set_last_error(&e);
+            std::ptr::null_mut()
         }
-        None => CString::new("Model not loaded").unwrap().into_raw(),
     }
 }
 
+fn catch_panic<T>(body: impl FnOnce() -> Result<T>) -> Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|msg| msg.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        Err(LlmError::Panic(message))
+    })
+}
+
+fn str_from_c<'a>(ptr: *const c_char, name: &str) -> Result<&'a str> {
+    if ptr.is_null() {
+        return Err(LlmError::InvalidArgument(format!("{} is null", name)));
+    }
+    unsafe { CStr::from_ptr(ptr) }
+        .to_str()
+        .map_err(|_| LlmError::InvalidArgument(format!("{} is not valid UTF-8", name)))
+}
+
+fn optional_str_from_c<'a>(ptr: *const c_char, name: &str) -> Result<Option<&'a str>> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+    str_from_c(ptr, name).map(Some)
+}
+
+/// Checks an out-parameter and nulls it so it is never left dangling on error.
+fn clear_out(out: *mut *mut c_char) -> Result<()> {
+    if out.is_null() {
+        return Err(LlmError::InvalidArgument("out is null".to_string()));
+    }
+    unsafe { *out = std::ptr::null_mut() };
+    Ok(())
+}
+
+fn write_out(out: *mut *mut c_char, text: &str) {
+    let text = CString::new(text.replace('\0', "")).unwrap_or_default();
+    unsafe { *out = text.into_raw() };
+}
+
+/// Parses per-call settings on top of the model's defaults.
+fn sampling_params_from_c(params_json: *const c_char, defaults: &SamplingParams) -> Result<SamplingParams> {
+    match optional_str_from_c(params_json, "params")? {
+        Some(json) => SamplingParams::from_json_with_defaults(json, defaults),
+        None => Ok(defaults.clone()),
+    }
+}
+
+// `s` must be a string this library returned; only null is caught
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn free_string_c(s: *mut c_char) {
-    unsafe {
-        if s.is_null() { return }
-        drop(CString::from_raw(s));
-    };
+    ffi_status(|| {
+        if !s.is_null() {
+            unsafe { drop(CString::from_raw(s)) };
+        }
+        Ok(())
+    });
 }
 
+/// Tokenizes `text` with the loaded model's tokenizer, special tokens
+/// included, and stores the number of ids in `*length`. Free the array with
+/// `free_array`. Returns null on failure.
 #[no_mangle]
 pub extern "C" fn tokenize_text_c(text: *const c_char, length: *mut usize) -> *mut u32 {
-    let text_str = unsafe {
-        if text.is_null() || length.is_null() {
-            return std::ptr::null_mut();
-        }
-        match CStr::from_ptr(text).to_str() {
-            Ok(s) => s,
-            Err(_) => return std::ptr::null_mut(),
-        }
+    ffi_ptr(|| tokenize_text(default_model(), text, length))
+}
+
+/// Turns `length` token ids back into text with the loaded model's
+/// tokenizer, leaving out special tokens. Free the result with
+/// `free_string_c`. Returns null on failure.
+#[no_mangle]
+pub extern "C" fn detokenize_ids(tokens: *const u32, length: usize) -> *mut c_char {
+    ffi_ptr(|| detokenize(default_model(), tokens, length))
+}
+
+/// Stores the number of tokens `text` takes up with the loaded model's
+/// tokenizer, special tokens included, in `*count`.
+#[no_mangle]
+pub extern "C" fn count_tokens_c(text: *const c_char, count: *mut usize) -> LlmStatus {
+    ffi_status(|| count_tokens(default_model(), text, count))
+}
+
+/// `tokenize_text_c` with the given model's tokenizer.
+#[no_mangle]
+pub extern "C" fn llm_model_tokenize(model: *mut ModelHandle, text: *const c_char, length: *mut usize) -> *mut u32 {
+    ffi_ptr(|| tokenize_text(handle_from_c(model), text, length))
+}
+
+/// `detokenize_ids` with the given model's tokenizer.
+#[no_mangle]
+pub extern "C" fn llm_model_detokenize(model: *mut ModelHandle, tokens: *const u32, length: usize) -> *mut c_char {
+    ffi_ptr(|| detokenize(handle_from_c(model), tokens, length))
+}
+
+/// `count_tokens_c` with the given model's tokenizer.
+#[no_mangle]
+pub extern "C" fn llm_model_count_tokens(model: *mut ModelHandle, text: *const c_char, count: *mut usize) -> LlmStatus {
+    ffi_status(|| count_tokens(handle_from_c(model), text, count))
+}
+
+/// Tokenizes `text` with the loaded model's tokenizer and stores a JSON
+/// array in `*out` (free it with `free_string_c`), one object per token:
+/// `{"id", "piece", "byte_start", "byte_end", "char_start", "char_end",
+/// "special"}`. Ranges are half-open and point into `text`; `char_*` count
+/// Unicode scalar values. Special tokens added around the text, such as
+/// BOS, are included when `add_special_tokens` is set and have an empty
+/// range at 0.
+#[no_mangle]
+pub extern "C" fn tokenize_with_offsets_c(
+    text: *const c_char,
+    add_special_tokens: bool,
+    out: *mut *mut c_char,
+) -> LlmStatus {
+    ffi_status(|| tokenize_with_offsets(default_model(), text, add_special_tokens, out))
+}
+
+/// `tokenize_with_offsets_c` with the given model's tokenizer.
+#[no_mangle]
+pub extern "C" fn llm_model_tokenize_with_offsets(
+    model: *mut ModelHandle,
+    text: *const c_char,
+    add_special_tokens: bool,
+    out: *mut *mut c_char,
+) -> LlmStatus {
+    ffi_status(|| tokenize_with_offsets(handle_from_c(model), text, add_special_tokens, out))
+}
+
+/// Stores a JSON description of the loaded model's vocabulary in `*out`
+/// (free it with `free_string_c`): `{"vocab_size", "bos_token_id",
+/// "eos_token_id", "pad_token_id", "unk_token_id", "added_tokens"}`. The
+/// token ids are null when the model has no such token; `added_tokens` is
+/// a list of `{"id", "content", "special"}` sorted by id.
+#[no_mangle]
+pub extern "C" fn vocab_info_c(out: *mut *mut c_char) -> LlmStatus {
+    ffi_status(|| vocab_info(default_model(), out))
+}
+
+/// Stores the id of `token` in `*id`. `token` is the vocabulary entry, e.g.
+/// `▁hello` or `<|im_end|>`, not text to tokenize.
+#[no_mangle]
+pub extern "C" fn token_to_id_c(token: *const c_char, id: *mut u32) -> LlmStatus {
+    ffi_status(|| token_to_id(default_model(), token, id))
+}
+
+/// The vocabulary entry for `id`. Free it with `free_string_c`. Returns null
+/// on failure.
+#[no_mangle]
+pub extern "C" fn id_to_token_c(id: u32) -> *mut c_char {
+    ffi_ptr(|| id_to_token(default_model(), id))
+}
+
+/// `vocab_info_c` for the given model.
+#[no_mangle]
+pub extern "C" fn llm_model_vocab_info(model: *mut ModelHandle, out: *mut *mut c_char) -> LlmStatus {
+    ffi_status(|| vocab_info(handle_from_c(model), out))
+}
+
+/// `token_to_id_c` with the given model's tokenizer.
+#[no_mangle]
+pub extern "C" fn llm_model_token_to_id(model: *mut ModelHandle, token: *const c_char, id: *mut u32) -> LlmStatus {
+    ffi_status(|| token_to_id(handle_from_c(model), token, id))
+}
+
+/// `id_to_token_c` with the given model's tokenizer.
+#[no_mangle]
+pub extern "C" fn llm_model_id_to_token(model: *mut ModelHandle, id: u32) -> *mut c_char {
+    ffi_ptr(|| id_to_token(handle_from_c(model), id))
+}
+
+fn tokenize_text(model: Result<Arc<ModelHandle>>, text: *const c_char, length: *mut usize) -> Result<*mut u32> {
+    if length.is_null() {
+        return Err(LlmError::InvalidArgument("length is null".to_string()));
+    }
+    let text_str = str_from_c(text, "text")?;
+    let tokens = tokenizer::tokenize(model?.tokenizer(), text_str, true)?;
+    unsafe { *length = tokens.len() };
+    Ok(Box::into_raw(tokens.into_boxed_slice()) as *mut u32)
+}
+
+fn detokenize(model: Result<Arc<ModelHandle>>, tokens: *const u32, length: usize) -> Result<*mut c_char> {
+    let ids = match length {
+        0 => &[][..],
+        _ if tokens.is_null() => return Err(LlmError::InvalidArgument("tokens is null".to_string())),
+        _ => unsafe { std::slice::from_raw_parts(tokens, length) },
     };
+    let text = tokenizer::detokenize(model?.tokenizer(), ids, true)?;
+    Ok(CString::new(text.replace('\0', "")).unwrap_or_default().into_raw())
+}
 
-    match tokenizer::tokenize(text_str) {
-        Ok(tokens) => {
-            unsafe { *length = tokens.len() };
-            let mut vec = tokens.into_boxed_slice();
-            let ptr = vec.as_mut_ptr();
-            std::mem::forget(vec);
-            ptr
-        }
-        Err(_) => std::ptr::null_mut(),
+fn tokenize_with_offsets(
+    model: Result<Arc<ModelHandle>>,
+    text: *const c_char,
+    add_special_tokens: bool,
+    out: *mut *mut c_char,
+) -> Result<()> {
+    clear_out(out)?;
+    let text_str = str_from_c(text, "text")?;
+    let spans = tokenizer::token_spans(model?.tokenizer(), text_str, add_special_tokens)?;
+    let json = serde_json::to_string(&spans)
+        .map_err(|e| LlmError::Tokenizer(format!("Failed to serialize tokens: {}", e)))?;
+    write_out(out, &json);
+    Ok(())
+}
+
+fn count_tokens(model: Result<Arc<ModelHandle>>, text: *const c_char, count: *mut usize) -> Result<()> {
+    if count.is_null() {
+        return Err(LlmError::InvalidArgument("count is null".to_string()));
     }
+    let text_str = str_from_c(text, "text")?;
+    let n = tokenizer::count_tokens(model?.tokenizer(), text_str)?;
+    unsafe { *count = n };
+    Ok(())
+}
+
+fn vocab_info(model: Result<Arc<ModelHandle>>, out: *mut *mut c_char) -> Result<()> {
+    clear_out(out)?;
+    let model = model?;
+    let info = vocab::vocab_info(model.tokenizer(), model.special_tokens());
+    let json = serde_json::to_string(&info)
+        .map_err(|e| LlmError::Tokenizer(format!("Failed to serialize vocabulary: {}", e)))?;
+    write_out(out, &json);
+    Ok(())
+}
+
+fn token_to_id(model: Result<Arc<ModelHandle>>, token: *const c_char, id: *mut u32) -> Result<()> {
+    if id.is_null() {
+        return Err(LlmError::InvalidArgument("id is null".to_string()));
+    }
+    let token_str = str_from_c(token, "token")?;
+    let token_id = vocab::token_to_id(model?.tokenizer(), token_str)?;
+    unsafe { *id = token_id };
+    Ok(())
+}
+
+fn id_to_token(model: Result<Arc<ModelHandle>>, id: u32) -> Result<*mut c_char> {
+    let token = vocab::id_to_token(model?.tokenizer(), id)?;
+    Ok(CString::new(token.replace('\0', "")).unwrap_or_default().into_raw())
 }
 
 #[no_mangle]
 pub extern "C" fn free_array(ptr: *mut u32, length: usize) {
-    if !ptr.is_null() {
-        unsafe {
-            let _ = Box::from_raw(std::slice::from_raw_parts_mut(ptr, length));
+    ffi_status(|| {
+        if !ptr.is_null() {
+            unsafe {
+                let _ = Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, length));
+            }
         }
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+
+    fn last_error() -> String {
+        let ptr = last_error_c();
+        assert!(!ptr.is_null(), "no error recorded");
+        unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn null_arguments_are_rejected() {
+        assert_eq!(load_model_c(ptr::null()), LlmStatus::InvalidArgument);
+        assert!(last_error().contains("model name is null"));
+
+        let input = CString::new("hi").unwrap();
+        assert_eq!(run_inference_c(input.as_ptr(), ptr::null_mut()), LlmStatus::InvalidArgument);
+        assert!(last_error().contains("out is null"));
+
+        assert_eq!(
+            run_inference_stream_c(input.as_ptr(), ptr::null(), None, ptr::null_mut()),
+            LlmStatus::InvalidArgument
+        );
+        let session = chat_session_create_c(ptr::null());
+        assert!(!session.is_null());
+        assert_eq!(chat_session_append_c(session, ptr::null(), input.as_ptr()), LlmStatus::InvalidArgument);
+        assert!(last_error().contains("role is null"));
+        chat_session_destroy_c(session);
+    }
+
+    #[test]
+    fn invalid_utf8_is_rejected() {
+        let bytes = [0xffu8, 0xfe, 0];
+        assert_eq!(download_model_c(bytes.as_ptr() as *const c_char), LlmStatus::InvalidArgument);
+        assert!(last_error().contains("not valid UTF-8"));
+    }
+
+    #[test]
+    fn traversal_ids_are_rejected() {
+        let name = CString::new("../../etc").unwrap();
+        assert_eq!(download_model_c(name.as_ptr()), LlmStatus::InvalidModelId);
+        assert!(llm_model_load(name.as_ptr()).is_null());
+        assert!(last_error().starts_with("Invalid model id"));
+
+        let repo = CString::new("TheBloke/TinyLlama-1.1B-Chat-v1.0-GGUF").unwrap();
+        let filename = CString::new("../../model.gguf").unwrap();
+        assert_eq!(download_gguf_model_c(repo.as_ptr(), filename.as_ptr(), ptr::null()), LlmStatus::InvalidModelId);
+    }
+
+    #[test]
+    fn calls_without_a_model_report_not_loaded() {
+        let input = CString::new("hi").unwrap();
+        let mut out = ptr::dangling_mut::<c_char>();
+        assert_eq!(run_inference_c(input.as_ptr(), &mut out), LlmStatus::NotLoaded);
+        assert!(out.is_null());
+        assert_eq!(last_error(), "Model not loaded");
+    }
+
+    #[test]
+    fn panics_become_a_status() {
+        assert_eq!(ffi_status(|| panic!("boom")), LlmStatus::Panic);
+        assert_eq!(last_error(), "Internal error: boom");
+
+        let value = 42;
+        assert_eq!(ffi_status(|| panic!("bad value {}", value)), LlmStatus::Panic);
+        assert_eq!(last_error(), "Internal error: bad value 42");
+
+        assert!(ffi_ptr::<u32>(|| panic!("boom")).is_null());
+        assert_eq!(last_error(), "Internal error: boom");
+    }
+
+    #[test]
+    fn success_clears_the_last_error() {
+        assert_eq!(ffi_status(|| Err(LlmError::NotLoaded)), LlmStatus::NotLoaded);
+        assert_eq!(ffi_status(|| Ok(())), LlmStatus::Ok);
+        assert!(last_error_c().is_null());
+    }
+
+    #[test]
+    fn poisoned_locks_are_recovered() {
+        let mutex = Mutex::new(1);
+        let _ = panic::catch_unwind(|| {
+            let _guard = mutex.lock().unwrap();
+            panic!("poison");
+        });
+        assert!(mutex.is_poisoned());
+        *lock(&mutex) += 1;
+        assert_eq!(*lock(&mutex), 2);
+        assert!(!mutex.is_poisoned());
+
+        // Poison the global the same way a panicking load would
+        assert_eq!(
+            ffi_status(|| {
+                let _model = lock(&MODEL);
+                panic!("poison");
+            }),
+            LlmStatus::Panic
+        );
+        assert_eq!(reset_cache_c(), LlmStatus::NotLoaded);
+        assert!(!cancel_inference_c());
+    }
+
+    #[test]
+    fn tokenizer_calls_check_arguments_before_the_model() {
+        let text = CString::new("hello").unwrap();
+        let mut length = 0;
+        assert!(tokenize_text_c(text.as_ptr(), ptr::null_mut()).is_null());
+        assert!(last_error().contains("length is null"));
+        assert!(tokenize_text_c(text.as_ptr(), &mut length).is_null());
+        assert_eq!(last_error(), "Model not loaded");
+        assert!(detokenize_ids(ptr::null(), 2).is_null());
+        assert!(last_error().contains("tokens is null"));
+        assert_eq!(count_tokens_c(text.as_ptr(), &mut length), LlmStatus::NotLoaded);
+        assert_eq!(llm_model_count_tokens(ptr::null_mut(), text.as_ptr(), &mut length), LlmStatus::InvalidArgument);
+        assert_eq!(tokenize_with_offsets_c(text.as_ptr(), true, ptr::null_mut()), LlmStatus::InvalidArgument);
+    }
+
+    #[test]
+    fn vocab_calls_check_arguments_before_the_model() {
+        let token = CString::new("<s>").unwrap();
+        let mut id = 0;
+        let mut out = ptr::null_mut();
+        assert_eq!(vocab_info_c(ptr::null_mut()), LlmStatus::InvalidArgument);
+        assert_eq!(vocab_info_c(&mut out), LlmStatus::NotLoaded);
+        assert!(out.is_null());
+        assert_eq!(token_to_id_c(token.as_ptr(), ptr::null_mut()), LlmStatus::InvalidArgument);
+        assert_eq!(token_to_id_c(ptr::null(), &mut id), LlmStatus::InvalidArgument);
+        assert_eq!(token_to_id_c(token.as_ptr(), &mut id), LlmStatus::NotLoaded);
+        assert!(id_to_token_c(1).is_null());
+        assert_eq!(last_error(), "Model not loaded");
+        assert_eq!(llm_model_vocab_info(ptr::null_mut(), &mut out), LlmStatus::InvalidArgument);
+        assert!(llm_model_id_to_token(ptr::null_mut(), 1).is_null());
+        assert!(last_error().contains("model is null"));
+    }
+
+    #[test]
+    fn model_info_and_unload_without_a_model() {
+        let mut out = ptr::null_mut();
+        assert_eq!(model_info_c(&mut out), LlmStatus::NotLoaded);
+        assert!(out.is_null());
+        assert_eq!(model_info_c(ptr::null_mut()), LlmStatus::InvalidArgument);
+        assert_eq!(unload_model_c(), LlmStatus::Ok);
+        assert_eq!(llm_model_info(ptr::null_mut(), &mut out), LlmStatus::InvalidArgument);
+    }
+
+    #[test]
+    fn interior_nuls_are_stripped() {
+        let mut out = ptr::null_mut();
+        write_out(&mut out, "a\0b");
+        assert_eq!(unsafe { CStr::from_ptr(out) }.to_str().unwrap(), "ab");
+        free_string_c(out);
+
+        set_last_error(&LlmError::Inference("bad\0output".to_string()));
+        assert_eq!(last_error(), "Inference error: badoutput");
+    }
+
+    #[test]
+    fn null_model_handles_are_rejected() {
+        assert!(llm_model_load(ptr::null()).is_null());
+        assert!(last_error().contains("model name is null"));
+
+        let input = CString::new("hi").unwrap();
+        let mut out = ptr::null_mut();
+        assert_eq!(
+            llm_model_run_inference(ptr::null_mut(), input.as_ptr(), ptr::null(), &mut out),
+            LlmStatus::InvalidArgument
+        );
+        assert_eq!(last_error(), "Invalid argument: model is null");
+        assert_eq!(llm_model_reset_cache(ptr::null_mut()), LlmStatus::InvalidArgument);
+        assert!(!llm_model_cancel(ptr::null_mut()));
+    }
+
+    #[test]
+    fn freeing_null_is_a_no_op() {
+        free_string_c(ptr::null_mut());
+        free_array(ptr::null_mut(), 3);
+        chat_session_destroy_c(ptr::null_mut());
+        llm_model_free(ptr::null_mut());
     }
 }
@@ -1,17 +1,68 @@
-use candle_core::{Device, DType, Result, Tensor};
-use candle_transformers::models::llama::{Llama, Config, Cache};
+use candle_core::{Device, Tensor};
 use candle_nn::VarBuilder;
 use tokenizers::Tokenizer;
-use std::path::Path;
-use hf_hub::api::sync::ApiBuilder;
-use serde_json::Value;
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use crate::sampler::{Sampler, SamplingParams};
+use crate::inference::{CancellationToken, FinishReason, GenerationOutput, GenerationStats, StopSequenceFilter};
+use crate::chat_template::{self, ChatTemplate};
+use crate::causal_lm::{self, Architecture, CausalLM, MemoryFootprint};
+use crate::config::{GenerationConfig, ModelConfig};
+use crate::context;
+use crate::downloader;
+use crate::error::{LlmError, Result};
+use crate::session::{ChatMessage, Role};
+use crate::storage;
+use crate::tokenizer::{self, IncrementalDecoder};
+use crate::vocab::SpecialTokens;
+
+const SINGLE_WEIGHTS_FILE: &str = "model.safetensors";
+const WEIGHTS_INDEX_FILE: &str = "model.safetensors.index.json";
+/// Rough cost of one forward pass per token, in tokens of a single prefill
+/// pass. Decides whether models that cannot append several tokens in one
+/// pass step through a new chat turn or prefill the whole history again.
+const STEP_COST_IN_PREFILL_TOKENS: usize = 8;
+
+static NEXT_MODEL_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The parts of `model.safetensors.index.json` we need: tensor name to shard file.
+#[derive(Deserialize)]
+struct WeightsIndex {
+    weight_map: HashMap<String, String>,
+}
 
 pub struct Model {
-    pub model: Llama,
-    pub tokenizer: Tokenizer,
-    pub cache: Cache,
-    pub config: Config,
+    /// Unique per load, so chat sessions can tell whose token ids they hold.
+    pub id: u64,
+    pub model: Box<dyn CausalLM>,
+    pub architecture: Architecture,
+    /// Shared with the `ModelHandle`, so tokenizing never waits for a
+    /// running generation.
+    pub tokenizer: Arc<Tokenizer>,
+    pub chat_template: ChatTemplate,
     pub name: String,
+    /// Directory holding the model's downloaded files.
+    pub model_dir: PathBuf,
+    /// Number of tokens currently held in the KV cache, i.e. the position
+    /// the next forward pass starts at. Shared with the `ModelHandle` so it
+    /// can be read during a generation.
+    cache_len: Arc<AtomicUsize>,
+    /// Chat session whose conversation is in `cache`, if any.
+    pub cache_owner: Option<u64>,
+    /// Token ids that end a response.
+    pub eos_token_ids: Vec<u32>,
+    /// Context window in tokens (`max_position_embeddings`).
+    pub context_length: usize,
+    /// Sampling settings used when a call does not override them, taken
+    /// from `generation_config.json`.
+    pub default_params: SamplingParams,
+    pub memory: MemoryFootprint,
+    /// BOS/EOS/PAD/UNK ids, shared with the `ModelHandle` like `tokenizer`.
+    pub special_tokens: SpecialTokens,
 }
 
 impl Model {
@@ -19,111 +70,293 @@ impl Model {
         let device = Device::Cpu;
         println!("Using device: {:?}", device);
         
-        // Create base models directory first
-        let models_dir = Path::new("models");
-        if !models_dir.exists() {
-            std::fs::create_dir_all(models_dir)
-                .map_err(|e| candle_core::Error::Msg(format!("Failed to create models directory: {}", e)))?;
-        }
-        
-        // Then create model-specific directory
-        let model_dir = models_dir.join(model_name);
-        if !model_dir.exists() {
-            std::fs::create_dir_all(&model_dir)
-                .map_err(|e| candle_core::Error::Msg(format!("Failed to create model directory: {}", e)))?;
-        }
+        let model_dir = storage::model_dir(model_name)?;
         
-        let model_path = model_dir.join("model.safetensors");
-        let tokenizer_path = model_dir.join("tokenizer.json");
+        let tokenizer_config_path = model_dir.join("tokenizer_config.json");
         let config_path = model_dir.join("config.json");
 
         // Download files if they don't exist
-        Self::download_file(model_name, "model.safetensors", &model_path)?;
-        Self::download_file(model_name, "tokenizer.json", &tokenizer_path)?;
+        let weight_paths = Self::download_weights(model_name, &model_dir)?;
+        Self::download_tokenizer(model_name, &model_dir)?;
         Self::download_file(model_name, "config.json", &config_path)?;
+        Self::download_optional_file(model_name, "tokenizer_config.json", &tokenizer_config_path);
+        Self::download_optional_file(model_name, "generation_config.json", &model_dir.join("generation_config.json"));
 
-        let tokenizer = Tokenizer::from_file(&tokenizer_path)
-            .map_err(|e| candle_core::Error::Msg(format!("Failed to load tokenizer: {}", e)))?;
+        let tokenizer = Arc::new(tokenizer::load_from_dir(&model_dir)?);
+        let chat_template = Self::load_chat_template(model_name, &tokenizer_config_path, &tokenizer)?;
 
-        let config = Self::load_config(&config_path)?;
-        let cache = Cache::new(true, DType::F16, &config, &device)?;
+        let architecture = Architecture::detect(&config_path)?;
+        let config = ModelConfig::from_file(&config_path)?;
+        println!("Detected architecture: {:?}", architecture);
 
         let vb = unsafe {
-            VarBuilder::from_mmaped_safetensors(&[model_path], DType::F16, &device)?
+            VarBuilder::from_mmaped_safetensors(&weight_paths, architecture.dtype(), &device)
+                .map_err(|e| LlmError::Load(e.to_string()))?
         };
         
-        let model = Llama::load(vb, &cache, &config)?;
+        let model = architecture.load(&config_path, &config, vb)?;
+        let memory = MemoryFootprint {
+            weights_bytes: causal_lm::safetensors_weights_bytes(&weight_paths, architecture.dtype())?,
+            kv_cache_bytes_per_token: config.kv_cache_bytes_per_token(architecture.dtype()),
+        };
+        let (eos_token_ids, default_params) =
+            Self::generation_settings(&model_dir, Some(&config), &tokenizer, &chat_template)?;
+        let special_tokens =
+            SpecialTokens::resolve(&tokenizer, &model_dir.join("tokenizer_config.json"), &eos_token_ids);
 
         Ok(Model {
+            id: NEXT_MODEL_ID.fetch_add(1, Ordering::Relaxed),
             model,
             tokenizer,
-            cache,
-            config,
+            architecture,
+            chat_template,
             name: model_name.to_string(),
+            model_dir,
+            cache_len: Arc::default(),
+            cache_owner: None,
+            eos_token_ids,
+            context_length: config.max_position_embeddings,
+            default_params,
+            memory,
+            special_tokens,
         })
     }
 
+    /// Loads a quantized GGUF file from `repo_id`. GGUF repos usually ship
+    /// no `tokenizer.json`, so the tokenizer files come from
+    /// `tokenizer_repo` (e.g. the original unquantized model) when given.
+    pub fn load_gguf_from_hub(repo_id: &str, filename: &str, tokenizer_repo: Option<&str>) -> Result<Self> {
+        Self::download_gguf_if_needed(repo_id, filename, tokenizer_repo)?;
+        let model_dir = storage::model_dir(repo_id)?;
+        Self::load_gguf(repo_id, &model_dir, &model_dir.join(filename))
+    }
+
+    /// Loads a model from `path`, which is either a `.gguf` file, a single
+    /// `.safetensors` file or a `model.safetensors.index.json` listing
+    /// shards. The tokenizer and config are read from the same directory.
     pub fn load(path: &Path) -> Result<Self> {
         let device = Device::Cpu;
         println!("Using device: {:?}", device);
         
         let model_dir = path.parent().unwrap();
         let model_name = model_dir.file_name().unwrap().to_str().unwrap();
+
+        if path.extension().is_some_and(|ext| ext == "gguf") {
+            return Self::load_gguf(model_name, model_dir, path);
+        }
         
-        let tokenizer_path = model_dir.join("tokenizer.json");
         let config_path = model_dir.join("config.json");
+        let weight_paths = Self::local_weights(path)?;
 
-        let tokenizer = Tokenizer::from_file(&tokenizer_path)
-            .map_err(|e| candle_core::Error::Msg(format!("Failed to load tokenizer: {}", e)))?;
+        let tokenizer = Arc::new(tokenizer::load_from_dir(model_dir)?);
+        let chat_template = Self::load_chat_template(model_name, &model_dir.join("tokenizer_config.json"), &tokenizer)?;
 
-        let config = Self::load_config_from_file(&config_path)?;
-        
-        let cache = Cache::new(true, DType::F16, &config, &device)?;
+        let architecture = Architecture::detect(&config_path)?;
+        let config = ModelConfig::from_file(&config_path)?;
+        println!("Detected architecture: {:?}", architecture);
 
         let vb = unsafe {
-            VarBuilder::from_mmaped_safetensors(&[path.to_path_buf()], DType::F16, &device)?
+            VarBuilder::from_mmaped_safetensors(&weight_paths, architecture.dtype(), &device)
+                .map_err(|e| LlmError::Load(e.to_string()))?
         };
         
-        let model = Llama::load(vb, &cache, &config)?;
+        let model = architecture.load(&config_path, &config, vb)?;
+        let memory = MemoryFootprint {
+            weights_bytes: causal_lm::safetensors_weights_bytes(&weight_paths, architecture.dtype())?,
+            kv_cache_bytes_per_token: config.kv_cache_bytes_per_token(architecture.dtype()),
+        };
+        let (eos_token_ids, default_params) =
+            Self::generation_settings(model_dir, Some(&config), &tokenizer, &chat_template)?;
+        let special_tokens =
+            SpecialTokens::resolve(&tokenizer, &model_dir.join("tokenizer_config.json"), &eos_token_ids);
 
         Ok(Model {
+            id: NEXT_MODEL_ID.fetch_add(1, Ordering::Relaxed),
             model,
             tokenizer,
-            cache,
-            config,
+            architecture,
+            chat_template,
             name: model_name.to_string(),
+            model_dir: model_dir.to_path_buf(),
+            cache_len: Arc::default(),
+            cache_owner: None,
+            eos_token_ids,
+            context_length: config.max_position_embeddings,
+            default_params,
+            memory,
+            special_tokens,
         })
     }
 
-    pub fn download_if_needed(model_id: &str) -> Result<()> {
-        // Create base models directory first
-        let models_dir = Path::new("models");
-        if !models_dir.exists() {
-            std::fs::create_dir_all(models_dir)
-                .map_err(|e| candle_core::Error::Msg(format!("Failed to create models directory: {}", e)))?;
-        }
-        
-        // Then create model-specific directory
-        let model_dir = models_dir.join(model_id);
-        if !model_dir.exists() {
-            std::fs::create_dir_all(&model_dir)
-                .map_err(|e| candle_core::Error::Msg(format!("Failed to create model directory: {}", e)))?;
+    fn load_gguf(model_name: &str, model_dir: &Path, gguf_path: &Path) -> Result<Self> {
+        let device = Device::Cpu;
+        println!("Using device: {:?}", device);
+
+        let tokenizer = Arc::new(tokenizer::load_from_dir(model_dir)?);
+        let chat_template = Self::load_chat_template(model_name, &model_dir.join("tokenizer_config.json"), &tokenizer)?;
+
+        let gguf = causal_lm::load_gguf(gguf_path, &device)?;
+        let (eos_token_ids, default_params) =
+            Self::generation_settings(model_dir, None, &tokenizer, &chat_template)?;
+        let special_tokens =
+            SpecialTokens::resolve(&tokenizer, &model_dir.join("tokenizer_config.json"), &eos_token_ids);
+
+        Ok(Model {
+            id: NEXT_MODEL_ID.fetch_add(1, Ordering::Relaxed),
+            model: gguf.model,
+            tokenizer,
+            architecture: gguf.architecture,
+            chat_template,
+            name: model_name.to_string(),
+            model_dir: model_dir.to_path_buf(),
+            cache_len: Arc::default(),
+            cache_owner: None,
+            eos_token_ids,
+            context_length: gguf.context_length,
+            default_params,
+            memory: gguf.memory,
+            special_tokens,
+        })
+    }
+
+    /// Collects the stop tokens and the model's sampling defaults.
+    fn generation_settings(
+        model_dir: &Path,
+        config: Option<&ModelConfig>,
+        tokenizer: &Tokenizer,
+        chat_template: &ChatTemplate,
+    ) -> Result<(Vec<u32>, SamplingParams)> {
+        let generation_config = GenerationConfig::from_file(&model_dir.join("generation_config.json"))?;
+        let eos_token_ids = Self::eos_token_ids(&generation_config, config, tokenizer, &chat_template.eos_token);
+        println!("EOS token ids: {:?}", eos_token_ids);
+
+        Ok((eos_token_ids, generation_config.sampling_defaults(SamplingParams::default())))
+    }
+
+    /// Merges the stop tokens of `generation_config.json`, `config.json` and
+    /// the tokenizer's eos token, falling back to `</s>` if none names any.
+    fn eos_token_ids(
+        generation_config: &GenerationConfig,
+        config: Option<&ModelConfig>,
+        tokenizer: &Tokenizer,
+        eos_token: &str,
+    ) -> Vec<u32> {
+        let mut eos_token_ids = generation_config.eos_token_ids();
+        eos_token_ids.extend(config.map(ModelConfig::eos_token_ids).unwrap_or_default());
+        eos_token_ids.extend(tokenizer.token_to_id(eos_token));
+        if eos_token_ids.is_empty() {
+            eos_token_ids.extend(tokenizer.token_to_id("</s>"));
         }
+        eos_token_ids.sort_unstable();
+        eos_token_ids.dedup();
+        eos_token_ids
+    }
+
+    pub fn download_if_needed(model_id: &str) -> Result<()> {
+        let model_dir = storage::model_dir(model_id)?;
         
-        let model_path = model_dir.join("model.safetensors");
-        let tokenizer_path = model_dir.join("tokenizer.json");
         let config_path = model_dir.join("config.json");
 
         // Only download if files don't exist
-        if !model_path.exists() {
-            Self::download_file(model_id, "model.safetensors", &model_path)?;
-        }
-        if !tokenizer_path.exists() {
-            Self::download_file(model_id, "tokenizer.json", &tokenizer_path)?;
-        }
+        Self::download_weights(model_id, &model_dir)?;
+        Self::download_tokenizer(model_id, &model_dir)?;
         if !config_path.exists() {
             Self::download_file(model_id, "config.json", &config_path)?;
         }
+        Self::download_optional_file(model_id, "tokenizer_config.json", &model_dir.join("tokenizer_config.json"));
+        Self::download_optional_file(model_id, "generation_config.json", &model_dir.join("generation_config.json"));
+
+        Ok(())
+    }
+
+    /// Downloads the safetensors weights of `model_id`, either a single
+    /// `model.safetensors` or every shard listed in
+    /// `model.safetensors.index.json`, and returns their local paths.
+    fn download_weights(model_id: &str, model_dir: &Path) -> Result<Vec<PathBuf>> {
+        let single_path = model_dir.join(SINGLE_WEIGHTS_FILE);
+        if single_path.exists() {
+            return Ok(vec![single_path]);
+        }
+
+        let index_path = model_dir.join(WEIGHTS_INDEX_FILE);
+        match Self::download_file(model_id, WEIGHTS_INDEX_FILE, &index_path) {
+            Ok(()) => {}
+            // Not sharded
+            Err(e) if e.is_not_found() => {
+                Self::download_file(model_id, SINGLE_WEIGHTS_FILE, &single_path)?;
+                return Ok(vec![single_path]);
+            }
+            Err(e) => return Err(e),
+        }
+
+        let shards = Self::read_weights_index(&index_path)?;
+        println!("{} is split into {} shards", model_id, shards.len());
+        shards
+            .iter()
+            .map(|shard| {
+                let shard_path = model_dir.join(shard);
+                Self::download_file(model_id, shard, &shard_path)?;
+                Ok(shard_path)
+            })
+            .collect()
+    }
+
+    /// Resolves the weight files for `Model::load`. A missing
+    /// `model.safetensors` falls back to the index next to it.
+    fn local_weights(path: &Path) -> Result<Vec<PathBuf>> {
+        let model_dir = path.parent().unwrap_or(Path::new("."));
+        let is_index = path.file_name().is_some_and(|name| name.to_string_lossy().ends_with(".index.json"));
+        let index_path = if is_index {
+            path.to_path_buf()
+        } else if !path.exists() && model_dir.join(WEIGHTS_INDEX_FILE).exists() {
+            model_dir.join(WEIGHTS_INDEX_FILE)
+        } else {
+            return Ok(vec![path.to_path_buf()]);
+        };
+
+        let shards = Self::read_weights_index(&index_path)?;
+        let paths: Vec<PathBuf> = shards.iter().map(|shard| model_dir.join(shard)).collect();
+        if let Some(missing) = paths.iter().find(|p| !p.exists()) {
+            return Err(LlmError::Load(format!("Missing weight shard: {}", missing.display())));
+        }
+        Ok(paths)
+    }
+
+    /// Returns the distinct shard file names listed in a
+    /// `model.safetensors.index.json`.
+    fn read_weights_index(index_path: &Path) -> Result<Vec<String>> {
+        let index_str = std::fs::read_to_string(index_path)
+            .map_err(|e| LlmError::Io(format!("Failed to read weights index: {}", e)))?;
+        let index: WeightsIndex = serde_json::from_str(&index_str)
+            .map_err(|e| LlmError::Config(format!("Failed to parse weights index: {}", e)))?;
+
+        let shards: BTreeSet<String> = index.weight_map.into_values().collect();
+        if shards.is_empty() {
+            return Err(LlmError::Config("Weights index lists no shards".to_string()));
+        }
+        for shard in &shards {
+            storage::validate_file_name(shard)?;
+        }
+        Ok(shards.into_iter().collect())
+    }
+
+    /// Downloads a GGUF file from `repo_id` plus the tokenizer files from
+    /// `tokenizer_repo` (defaults to `repo_id`) into the model's storage directory.
+    pub fn download_gguf_if_needed(repo_id: &str, filename: &str, tokenizer_repo: Option<&str>) -> Result<()> {
+        if !filename.ends_with(".gguf") {
+            return Err(LlmError::InvalidArgument(format!("Not a GGUF file: {}", filename)));
+        }
+        storage::validate_file_name(filename)?;
+        if let Some(tokenizer_repo) = tokenizer_repo {
+            storage::validate_model_id(tokenizer_repo)?;
+        }
+        let model_dir = storage::model_dir(repo_id)?;
+        let tokenizer_repo = tokenizer_repo.unwrap_or(repo_id);
+
+        Self::download_file(repo_id, filename, &model_dir.join(filename))?;
+        Self::download_tokenizer(tokenizer_repo, &model_dir)?;
+        Self::download_optional_file(tokenizer_repo, "tokenizer_config.json", &model_dir.join("tokenizer_config.json"));
+        Self::download_optional_file(tokenizer_repo, "generation_config.json", &model_dir.join("generation_config.json"));
 
         Ok(())
     }
@@ -136,153 +369,299 @@ impl Model {
             if let Some(parent) = save_path.parent() {
                 if !parent.exists() {
                     std::fs::create_dir_all(parent)
-                        .map_err(|e| candle_core::Error::Msg(format!("Failed to create directory: {}", e)))?;
+                        .map_err(|e| LlmError::Io(format!("Failed to create directory: {}", e)))?;
                 }
             }
             
-            let api = ApiBuilder::new()
-                .with_progress(true)
-                .build()
-                .map_err(|e| candle_core::Error::Msg(format!("API error: {}", e)))?;
-                
+            let api = downloader::hub_api()?;
             let repo = api.model(model_id.to_string());
             let file = repo.get(filename)
-                .map_err(|e| candle_core::Error::Msg(format!("Download error: {}", e)))?;
+                .map_err(|e| LlmError::from_hub(&format!("Failed to download {}", filename), e))?;
                 
-            std::fs::copy(file, save_path)
-                .map_err(|e| candle_core::Error::Msg(format!("Copy error: {}", e)))?;
+            storage::link_or_copy(&file, save_path)?;
         }
         Ok(())
     }
 
-    fn load_config(config_path: &Path) -> Result<Config> {
-        let config_str = std::fs::read_to_string(config_path)
-            .map_err(|e| candle_core::Error::Msg(format!("Failed to read config: {}", e)))?;
-            
-        let config_json: Value = serde_json::from_str(&config_str)
-            .map_err(|e| candle_core::Error::Msg(format!("Failed to parse config: {}", e)))?;
-
-        Ok(Config {
-            hidden_size: config_json["hidden_size"]
-                .as_i64()
-                .ok_or_else(|| candle_core::Error::Msg("missing hidden_size".to_string()))? as usize,
-            intermediate_size: config_json["intermediate_size"]
-                .as_i64()
-                .ok_or_else(|| candle_core::Error::Msg("missing intermediate_size".to_string()))? as usize,
-            vocab_size: config_json["vocab_size"]
-                .as_i64()
-                .ok_or_else(|| candle_core::Error::Msg("missing vocab_size".to_string()))? as usize,
-            num_hidden_layers: config_json["num_hidden_layers"]
-                .as_i64()
-                .ok_or_else(|| candle_core::Error::Msg("missing num_hidden_layers".to_string()))? as usize,
-            num_attention_heads: config_json["num_attention_heads"]
-                .as_i64()
-                .ok_or_else(|| candle_core::Error::Msg("missing num_attention_heads".to_string()))? as usize,
-            num_key_value_heads: config_json["num_key_value_heads"]
-                .as_i64()
-                .unwrap_or(config_json["num_attention_heads"].as_i64().unwrap_or(32)) as usize,
-            rms_norm_eps: config_json["rms_norm_eps"]
-                .as_f64()
-                .unwrap_or(1e-5),
-            rope_theta: config_json["rope_theta"]
-                .as_f64()
-                .unwrap_or(10000.0) as f32,
-            use_flash_attn: false,
-        })
+    /// Downloads `tokenizer.json`, or the SentencePiece `tokenizer.model`
+    /// for repos that only ship that; `tokenizer::load_from_dir` converts it.
+    fn download_tokenizer(model_id: &str, model_dir: &Path) -> Result<()> {
+        let sentencepiece_path = model_dir.join(tokenizer::SENTENCEPIECE_FILE);
+        if sentencepiece_path.exists() {
+            return Ok(());
+        }
+        match Self::download_file(model_id, tokenizer::TOKENIZER_FILE, &model_dir.join(tokenizer::TOKENIZER_FILE)) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                println!("No {} for {} ({}), trying {}", tokenizer::TOKENIZER_FILE, model_id, e, tokenizer::SENTENCEPIECE_FILE);
+                Self::download_file(model_id, tokenizer::SENTENCEPIECE_FILE, &sentencepiece_path).map_err(|_| e)
+            }
+        }
     }
 
-    fn load_config_from_file(path: &Path) -> Result<Config> {
-        Self::parse_config_file(path)
+    /// Like `download_file`, for files not every repo has.
+    fn download_optional_file(model_id: &str, filename: &str, save_path: &Path) {
+        if let Err(e) = Self::download_file(model_id, filename, save_path) {
+            println!("Skipping {} for {}: {}", filename, model_id, e);
+        }
     }
 
-    fn parse_config_file(path: &Path) -> Result<Config> {
-        let config_str = std::fs::read_to_string(path)
-            .map_err(|e| candle_core::Error::Msg(format!("Read error: {}", e)))?;
-            
-        let config_json: Value = serde_json::from_str(&config_str)
-            .map_err(|e| candle_core::Error::Msg(format!("JSON parse error: {}", e)))?;
-        
-        Ok(Config {
-            hidden_size: config_json["hidden_size"]
-                .as_i64()
-                .ok_or_else(|| candle_core::Error::Msg("missing hidden_size".to_string()))? as usize,
-            intermediate_size: config_json["intermediate_size"]
-                .as_i64()
-                .ok_or_else(|| candle_core::Error::Msg("missing intermediate_size".to_string()))? as usize,
-            vocab_size: config_json["vocab_size"]
-                .as_i64()
-                .ok_or_else(|| candle_core::Error::Msg("missing vocab_size".to_string()))? as usize,
-            num_hidden_layers: config_json["num_hidden_layers"]
-                .as_i64()
-                .ok_or_else(|| candle_core::Error::Msg("missing num_hidden_layers".to_string()))? as usize,
-            num_attention_heads: config_json["num_attention_heads"]
-                .as_i64()
-                .ok_or_else(|| candle_core::Error::Msg("missing num_attention_heads".to_string()))? as usize,
-            num_key_value_heads: config_json["num_key_value_heads"]
-                .as_i64()
-                .unwrap_or(config_json["num_attention_heads"].as_i64().unwrap_or(32)) as usize,
-            rms_norm_eps: config_json["rms_norm_eps"]
-                .as_f64()
-                .unwrap_or(1e-5),
-            rope_theta: config_json["rope_theta"]
-                .as_f64()
-                .unwrap_or(10000.0) as f32,
-            use_flash_attn: false,
-        })
+    fn load_chat_template(model_name: &str, tokenizer_config_path: &Path, tokenizer: &Tokenizer) -> Result<ChatTemplate> {
+        let template = ChatTemplate::from_tokenizer_config(tokenizer_config_path, tokenizer)?;
+        match chat_template::override_for(model_name) {
+            Some(source) => ChatTemplate::new(&source, &template.bos_token, &template.eos_token),
+            None => Ok(template),
+        }
+    }
+
+    /// Swaps in a different chat template, or restores the one from
+    /// `tokenizer_config.json` when `source` is `None`.
+    pub fn set_chat_template(&mut self, source: Option<&str>) -> Result<()> {
+        let base = ChatTemplate::from_tokenizer_config(&self.model_dir.join("tokenizer_config.json"), &self.tokenizer)?;
+        self.chat_template = match source {
+            Some(source) => ChatTemplate::new(source, &base.bos_token, &base.eos_token)?,
+            None => base,
+        };
+        Ok(())
     }
 
-    pub fn run_inference(&self, input: &str) -> Result<String> {
-        // Simpler chat format
-        let formatted_input = format!("### Human: {}\n### Assistant:", input);
-        let tokens = self.tokenizer.encode(formatted_input, true)
-            .map_err(|e| candle_core::Error::Msg(format!("Failed to tokenize: {}", e)))?;
+    /// Generates a response to an independent prompt and hands every newly
+    /// decoded text fragment to `on_text` as soon as it is available. The KV
+    /// cache is cleared first. Returns the raw decoded output, which is
+    /// partial if `cancel` fired.
+    pub fn generate(
+        &mut self,
+        input: &str,
+        params: &SamplingParams,
+        cancel: &CancellationToken,
+        on_text: &mut dyn FnMut(&str),
+    ) -> Result<GenerationOutput> {
+        let messages = [ChatMessage {
+            role: Role::User,
+            content: input.to_string(),
+        }];
+        let formatted_input = self.chat_template.render(&messages, true)?;
+        // The template already places BOS and other special tokens
+        let tokens = self.tokenizer.encode(formatted_input, false)
+            .map_err(|e| LlmError::Tokenizer(format!("Failed to tokenize: {}", e)))?;
         
-        let input_ids = tokens.get_ids();
+        let input_ids = self.fit_to_context(tokens.get_ids(), params)?;
         println!("Input tokens: {:?}", input_ids);
-        
-        let input_tensor = Tensor::new(input_ids, &Device::Cpu)?
-            .unsqueeze(0)?;
-        println!("Input tensor shape: {:?}", input_tensor.shape());
+
+        self.reset_cache()?;
+        self.generate_from_tokens(&input_ids, params, cancel, on_text)
+    }
+
+    /// Checks that `prompt_ids` leave room for the response in the context
+    /// window, truncating them as `params.context_overflow` says if not.
+    pub fn fit_to_context(&self, prompt_ids: &[u32], params: &SamplingParams) -> Result<Vec<u32>> {
+        let budget = self.prompt_budget(params);
+        let bos_token_id = self.tokenizer.token_to_id(&self.chat_template.bos_token);
+        let fitted = context::fit_to_context(prompt_ids, budget, self.context_length, bos_token_id, params.context_overflow)?;
+        if fitted.len() < prompt_ids.len() {
+            println!("Prompt truncated from {} to {} tokens", prompt_ids.len(), fitted.len());
+        }
+        Ok(fitted)
+    }
+
+    /// Number of prompt tokens that leave room for the response.
+    pub fn prompt_budget(&self, params: &SamplingParams) -> usize {
+        context::prompt_budget(self.context_length, params.max_new_tokens)
+    }
+
+    /// Feeds `prompt_ids` on top of whatever is already in the KV cache and
+    /// samples a response from there. The last sampled token is only fed back
+    /// if generation continues, so `cache_len` may trail the returned tokens
+    /// by one.
+    pub fn generate_from_tokens(
+        &mut self,
+        prompt_ids: &[u32],
+        params: &SamplingParams,
+        cancel: &CancellationToken,
+        on_text: &mut dyn FnMut(&str),
+    ) -> Result<GenerationOutput> {
+        if prompt_ids.is_empty() {
+            return Err(LlmError::InvalidArgument("Nothing to feed to the model".to_string()));
+        }
+        let start_time = Instant::now();
 
         println!("Generating response...");
         let mut generated_tokens = Vec::new();
-        let mut next_token = input_tensor;
+        let mut all_tokens = prompt_ids.to_vec();
+        let mut sampler = Sampler::new(params.clone()).with_vocab_size(tokenizer::id_bound(&self.tokenizer));
+        let tokenizer = self.tokenizer.clone();
+        let mut decoder = IncrementalDecoder::new(&tokenizer, true);
+        let mut text = StopSequenceFilter::new(&params.stop);
+        let mut finish_reason = FinishReason::Length;
+
+        // Prefill the prompt in one pass, then feed back one token at a time
+        let mut next_input = prompt_ids.to_vec();
         
-        for i in 0..100 {
-            let logits = self.model.forward(&next_token, i)?;
-            let logits = logits.squeeze(0)?;
-            let next_token_id = logits.argmax(0)?.to_vec0::<u32>()?;
+        for _ in 0..params.max_new_tokens {
+            if self.cache_len() + next_input.len() > self.context_length {
+                println!("Context window of {} tokens is full", self.context_length);
+                break;
+            }
+            if cancel.is_cancelled() {
+                println!("Generation cancelled after {} tokens", generated_tokens.len());
+                finish_reason = FinishReason::Cancelled;
+                break;
+            }
+
+            let logits = self.forward(&next_input)?;
+            let next_token_id = sampler.sample(&logits, &all_tokens)?;
             
-            // Stop conditions
-            if next_token_id == self.tokenizer.token_to_id("</s>").unwrap_or(0) 
-               || next_token_id == self.tokenizer.token_to_id("### Human").unwrap_or(0)
-               || next_token_id == self.tokenizer.token_to_id("###").unwrap_or(0) {
+            if self.eos_token_ids.contains(&next_token_id) {
+                finish_reason = FinishReason::Stop;
                 break;
             }
             
             generated_tokens.push(next_token_id);
-            next_token = Tensor::new(&[next_token_id], &Device::Cpu)?.unsqueeze(0)?;
+            all_tokens.push(next_token_id);
+
+            let released = text.push(&decoder.push(next_token_id)?);
+            if !released.is_empty() {
+                on_text(&released);
+            }
+            if text.stop_sequence().is_some() {
+                finish_reason = FinishReason::Stop;
+                break;
+            }
+
+            next_input = vec![next_token_id];
         }
 
-        // Decode and clean up the response
-        let output = self.tokenizer.decode(&generated_tokens, true)
-            .map_err(|e| candle_core::Error::Msg(format!("Failed to decode: {}", e)))?;
-
-        // Clean up the response more aggressively
-        let cleaned = output
-            .trim()
-            .trim_start_matches("### Assistant:")
-            .trim_start_matches("Assistant:")
-            .trim_start_matches("The answer is:")
-            .trim_start_matches("Answer:")
-            .trim()
-            .lines()
-            .take_while(|line| !line.starts_with("###"))
-            .collect::<Vec<_>>()
-            .join("\n")
-            .trim()
-            .to_string();
-
-        Ok(cleaned)
+        let tail = match text.stop_sequence() {
+            Some(_) => String::new(),
+            None => decoder.flush()?,
+        };
+        let (rest, output, stop_sequence) = text.finish(&tail);
+        if !rest.is_empty() {
+            on_text(&rest);
+        }
+
+        let stats = GenerationStats::new(
+            prompt_ids.len(),
+            generated_tokens.len(),
+            start_time.elapsed(),
+            finish_reason,
+        );
+        Ok(GenerationOutput {
+            text: output,
+            tokens: generated_tokens,
+            stats,
+            stop_sequence,
+        })
+    }
+
+    /// Whether `new_tokens` more tokens are cheap to feed on top of the
+    /// current cache, rather than clearing it and prefilling everything.
+    pub fn can_append(&self, new_tokens: usize) -> bool {
+        self.model.appends_in_one_pass()
+            || new_tokens * STEP_COST_IN_PREFILL_TOKENS <= self.cache_len() + new_tokens
+    }
+
+    /// Runs `tokens` through the model at the current cache position and
+    /// returns the logits for the last one.
+    pub fn forward(&mut self, tokens: &[u32]) -> Result<Tensor> {
+        let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+        let logits = self.model.forward(&input, self.cache_len())?;
+        self.cache_len.fetch_add(tokens.len(), Ordering::Relaxed);
+        Ok(logits)
+    }
+
+    /// Number of tokens in the KV cache.
+    pub fn cache_len(&self) -> usize {
+        self.cache_len.load(Ordering::Relaxed)
+    }
+
+    /// Handle on `cache_len` that stays readable while the model is locked.
+    pub fn shared_cache_len(&self) -> Arc<AtomicUsize> {
+        self.cache_len.clone()
+    }
+
+    /// Drops all keys and values from the KV cache so the next forward pass
+    /// starts again at position 0.
+    pub fn reset_cache(&mut self) -> Result<()> {
+        self.model.clear_kv_cache()?;
+        self.cache_len.store(0, Ordering::Relaxed);
+        self.cache_owner = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::tests::word_level;
+
+    const SHARDED_INDEX: &str = r#"{
+        "metadata": {"total_size": 16},
+        "weight_map": {
+            "lm_head.weight": "model-00002-of-00002.safetensors",
+            "model.embed_tokens.weight": "model-00001-of-00002.safetensors",
+            "model.norm.weight": "model-00002-of-00002.safetensors"
+        }
+    }"#;
+
+    #[test]
+    fn reads_the_shards_of_a_weights_index() {
+        let temp = tempfile::tempdir().unwrap();
+        let index_path = temp.path().join(WEIGHTS_INDEX_FILE);
+        std::fs::write(&index_path, SHARDED_INDEX).unwrap();
+        assert_eq!(
+            Model::read_weights_index(&index_path).unwrap(),
+            vec!["model-00001-of-00002.safetensors", "model-00002-of-00002.safetensors"]
+        );
+
+        std::fs::write(&index_path, r#"{"weight_map": {"a": "../outside.safetensors"}}"#).unwrap();
+        assert!(Model::read_weights_index(&index_path).is_err());
+        std::fs::write(&index_path, r#"{"weight_map": {}}"#).unwrap();
+        assert!(matches!(Model::read_weights_index(&index_path), Err(LlmError::Config(_))));
+    }
+
+    #[test]
+    fn finds_local_weights_through_the_index() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::write(dir.join(WEIGHTS_INDEX_FILE), SHARDED_INDEX).unwrap();
+        std::fs::write(dir.join("model-00001-of-00002.safetensors"), "").unwrap();
+
+        // A missing model.safetensors falls back to the index next to it
+        let err = Model::local_weights(&dir.join(SINGLE_WEIGHTS_FILE)).unwrap_err();
+        assert!(err.to_string().contains("model-00002-of-00002.safetensors"), "{}", err);
+
+        std::fs::write(dir.join("model-00002-of-00002.safetensors"), "").unwrap();
+        let shards = vec![dir.join("model-00001-of-00002.safetensors"), dir.join("model-00002-of-00002.safetensors")];
+        assert_eq!(Model::local_weights(&dir.join(SINGLE_WEIGHTS_FILE)).unwrap(), shards);
+        assert_eq!(Model::local_weights(&dir.join(WEIGHTS_INDEX_FILE)).unwrap(), shards);
+
+        std::fs::write(dir.join(SINGLE_WEIGHTS_FILE), "").unwrap();
+        assert_eq!(Model::local_weights(&dir.join(SINGLE_WEIGHTS_FILE)).unwrap(), vec![dir.join(SINGLE_WEIGHTS_FILE)]);
+    }
+
+    #[test]
+    fn only_a_missing_file_counts_as_not_found() {
+        let not_found = LlmError::from_hub("Failed to download x", "https://huggingface.co/x: status code 404");
+        assert!(not_found.is_not_found());
+        let denied = LlmError::from_hub("Failed to download x", "https://huggingface.co/x: status code 403");
+        assert!(!denied.is_not_found());
+        assert!(!LlmError::from_hub("Failed to download x", "Connection refused").is_not_found());
+    }
+
+    #[test]
+    fn merges_eos_token_ids_from_every_source() {
+        let generation_config: GenerationConfig = serde_json::from_str(r#"{"eos_token_id": [7, 5]}"#).unwrap();
+        let config = ModelConfig::parse(
+            r#"{"hidden_size": 64, "intermediate_size": 128, "vocab_size": 100,
+                "num_hidden_layers": 2, "num_attention_heads": 4, "eos_token_id": 5}"#,
+        )
+        .unwrap();
+        let tokenizer = word_level();
+
+        assert_eq!(Model::eos_token_ids(&generation_config, Some(&config), &tokenizer, "</s>"), vec![2, 5, 7]);
+        assert_eq!(Model::eos_token_ids(&generation_config, None, &tokenizer, "<|im_end|>"), vec![5, 7]);
+        // Nothing names one: the tokenizer's `</s>`
+        assert_eq!(Model::eos_token_ids(&GenerationConfig::default(), None, &tokenizer, "<|im_end|>"), vec![2]);
     }
 }
@@ -0,0 +1,358 @@
+use candle_core::{DType, Tensor};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use crate::context::ContextOverflow;
+use crate::error::{LlmError, Result};
+
+/// Settings that control how the next token is picked from the logits.
+///
+/// Penalties are applied to the raw logits first, then the sampling stages
+/// run in this order: top-k, temperature, top-p, min-p.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SamplingParams {
+    /// Softmax temperature. `0.0` (or below) means greedy decoding.
+    pub temperature: f32,
+    /// Keep only the `top_k` most likely tokens. `0` disables this stage.
+    pub top_k: usize,
+    /// Keep the smallest set of tokens whose cumulative probability reaches
+    /// `top_p`. `1.0` disables this stage.
+    pub top_p: f32,
+    /// Drop tokens less likely than `min_p` times the most likely one.
+    /// `0.0` disables this stage.
+    pub min_p: f32,
+    /// RNG seed for reproducible output. `None` seeds from entropy.
+    pub seed: Option<u64>,
+    /// Divides positive (multiplies negative) logits of tokens seen in the
+    /// penalty window. `1.0` disables this penalty.
+    pub repetition_penalty: f32,
+    /// Subtracted from a token's logit once per occurrence in the window.
+    pub frequency_penalty: f32,
+    /// Subtracted once from the logit of any token present in the window.
+    pub presence_penalty: f32,
+    /// How many of the most recent tokens the penalties look at.
+    /// `0` disables all penalties.
+    pub penalty_last_n: usize,
+    /// Upper bound on the number of tokens generated per call.
+    pub max_new_tokens: usize,
+    /// Strings that end the response when they appear in the decoded text.
+    /// The stop string itself is not part of the output.
+    pub stop: Vec<String>,
+    /// How to handle a prompt that does not fit the context window.
+    pub context_overflow: ContextOverflow,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        SamplingParams {
+            temperature: 0.8,
+            top_k: 40,
+            top_p: 0.95,
+            min_p: 0.05,
+            seed: None,
+            repetition_penalty: 1.1,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            penalty_last_n: 64,
+            max_new_tokens: 100,
+            stop: Vec::new(),
+            context_overflow: ContextOverflow::default(),
+        }
+    }
+}
+
+impl SamplingParams {
+    /// Parses settings from a JSON object. Fields missing from `json` are
+    /// taken from `defaults` (usually the model's `generation_config.json`
+    /// settings).
+    pub fn from_json_with_defaults(json: &str, defaults: &SamplingParams) -> Result<Self> {
+        let invalid = |e: serde_json::Error| LlmError::InvalidArgument(format!("sampling params: {}", e));
+        let overrides: Value = serde_json::from_str(json).map_err(invalid)?;
+        let Value::Object(overrides) = overrides else {
+            return Err(LlmError::InvalidArgument("sampling params: expected a JSON object".to_string()));
+        };
+
+        let mut merged = serde_json::to_value(defaults).map_err(invalid)?;
+        if let Value::Object(merged) = &mut merged {
+            merged.extend(overrides);
+        }
+        serde_json::from_value(merged).map_err(invalid)
+    }
+}
+
+pub struct Sampler {
+    params: SamplingParams,
+    rng: StdRng,
+    vocab_size: Option<usize>,
+}
+
+impl Sampler {
+    pub fn new(params: SamplingParams) -> Self {
+        let rng = match params.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Sampler { params, rng, vocab_size: None }
+    }
+
+    /// Never picks ids at or past `vocab_size`. Some models have more logits
+    /// than the tokenizer has tokens (Qwen2.5, Phi-2); the extra ids are
+    /// padding that decodes to nothing.
+    pub fn with_vocab_size(mut self, vocab_size: usize) -> Self {
+        self.vocab_size = Some(vocab_size);
+        self
+    }
+
+    /// Picks the next token. `history` holds the tokens seen so far (prompt
+    /// and generated), the last `penalty_last_n` of which are penalised.
+    pub fn sample(&mut self, logits: &Tensor, history: &[u32]) -> Result<u32> {
+        let mut logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        if let Some(vocab_size) = self.vocab_size {
+            logits.truncate(vocab_size);
+        }
+        self.apply_penalties(&mut logits, history);
+        self.sample_from_slice(&logits)
+    }
+
+    pub fn apply_penalties(&self, logits: &mut [f32], history: &[u32]) {
+        let params = &self.params;
+        if params.penalty_last_n == 0
+            || (params.repetition_penalty == 1.0
+                && params.frequency_penalty == 0.0
+                && params.presence_penalty == 0.0)
+        {
+            return;
+        }
+
+        let window = &history[history.len().saturating_sub(params.penalty_last_n)..];
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for &token in window {
+            *counts.entry(token).or_insert(0) += 1;
+        }
+
+        for (&token, &count) in &counts {
+            let Some(logit) = logits.get_mut(token as usize) else {
+                continue;
+            };
+            if params.repetition_penalty != 1.0 {
+                if *logit > 0.0 {
+                    *logit /= params.repetition_penalty;
+                } else {
+                    *logit *= params.repetition_penalty;
+                }
+            }
+            *logit -= count as f32 * params.frequency_penalty + params.presence_penalty;
+        }
+    }
+
+    pub fn sample_from_slice(&mut self, logits: &[f32]) -> Result<u32> {
+        if logits.is_empty() {
+            return Err(LlmError::Inference("Cannot sample from empty logits".to_string()));
+        }
+
+        if self.params.temperature <= 0.0 {
+            return Ok(argmax(logits));
+        }
+
+        let mut candidates: Vec<(u32, f32)> = logits
+            .iter()
+            .enumerate()
+            .map(|(id, &logit)| (id as u32, logit))
+            .collect();
+
+        // Top-k: only the k best logits survive
+        let top_k = self.params.top_k;
+        if top_k > 0 && top_k < candidates.len() {
+            candidates.select_nth_unstable_by(top_k - 1, |a, b| b.1.total_cmp(&a.1));
+            candidates.truncate(top_k);
+        }
+        candidates.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+
+        // Temperature + softmax, candidates are sorted so the first one is the max
+        let max_logit = candidates[0].1;
+        let mut probs: Vec<f32> = candidates
+            .iter()
+            .map(|&(_, logit)| ((logit - max_logit) / self.params.temperature).exp())
+            .collect();
+        let sum: f32 = probs.iter().sum();
+        probs.iter_mut().for_each(|p| *p /= sum);
+
+        // Top-p: cut the tail once the cumulative probability is reached
+        let mut keep = probs.len();
+        if self.params.top_p < 1.0 {
+            let mut cumulative = 0.0;
+            for (i, p) in probs.iter().enumerate() {
+                cumulative += p;
+                if cumulative >= self.params.top_p {
+                    keep = i + 1;
+                    break;
+                }
+            }
+        }
+
+        // Min-p: drop tokens far less likely than the best one
+        if self.params.min_p > 0.0 {
+            let threshold = probs[0] * self.params.min_p;
+            keep = probs[..keep].iter().take_while(|&&p| p >= threshold).count().max(1);
+        }
+
+        let total: f32 = probs[..keep].iter().sum();
+        let mut target = self.rng.gen::<f32>() * total;
+        for (i, p) in probs[..keep].iter().enumerate() {
+            target -= p;
+            if target <= 0.0 {
+                return Ok(candidates[i].0);
+            }
+        }
+        Ok(candidates[keep - 1].0)
+    }
+}
+
+fn argmax(logits: &[f32]) -> u32 {
+    logits
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(id, _)| id as u32)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GenerationConfig;
+    use std::collections::HashSet;
+
+    fn sampler(params: SamplingParams) -> Sampler {
+        Sampler::new(SamplingParams { seed: Some(42), ..params })
+    }
+
+    /// Only the sampling stage under test is enabled.
+    fn plain() -> SamplingParams {
+        SamplingParams { temperature: 1.0, top_k: 0, top_p: 1.0, min_p: 0.0, ..SamplingParams::default() }
+    }
+
+    /// Logits whose softmax is `probs`.
+    fn logits_of(probs: &[f32]) -> Vec<f32> {
+        probs.iter().map(|p| p.ln()).collect()
+    }
+
+    /// Every token `sampler` picks in 200 draws.
+    fn picks(sampler: &mut Sampler, logits: &[f32]) -> HashSet<u32> {
+        (0..200).map(|_| sampler.sample_from_slice(logits).unwrap()).collect()
+    }
+
+    #[test]
+    fn zero_temperature_is_greedy() {
+        let mut sampler = sampler(SamplingParams { temperature: 0.0, ..plain() });
+        assert_eq!(picks(&mut sampler, &[0.5, 3.0, 2.9, -1.0]), HashSet::from([1]));
+    }
+
+    #[test]
+    fn top_k_keeps_the_best_logits() {
+        let logits = [1.0, 5.0, 3.0, 4.0];
+        assert_eq!(picks(&mut sampler(SamplingParams { top_k: 1, ..plain() }), &logits), HashSet::from([1]));
+        assert_eq!(picks(&mut sampler(SamplingParams { top_k: 2, ..plain() }), &logits), HashSet::from([1, 3]));
+    }
+
+    #[test]
+    fn top_p_cuts_the_tail() {
+        let logits = logits_of(&[0.6, 0.3, 0.1]);
+        assert_eq!(picks(&mut sampler(SamplingParams { top_p: 0.5, ..plain() }), &logits), HashSet::from([0]));
+        assert_eq!(picks(&mut sampler(SamplingParams { top_p: 0.85, ..plain() }), &logits), HashSet::from([0, 1]));
+    }
+
+    #[test]
+    fn min_p_drops_unlikely_tokens() {
+        let logits = logits_of(&[0.6, 0.3, 0.1]);
+        assert_eq!(picks(&mut sampler(SamplingParams { min_p: 0.2, ..plain() }), &logits), HashSet::from([0, 1]));
+        assert_eq!(picks(&mut sampler(SamplingParams { min_p: 0.9, ..plain() }), &logits), HashSet::from([0]));
+    }
+
+    #[test]
+    fn seeded_sampling_is_reproducible() {
+        let logits = [1.0; 50];
+        let draw = |seed| {
+            let mut sampler = Sampler::new(SamplingParams { seed: Some(seed), ..plain() });
+            (0..20).map(|_| sampler.sample_from_slice(&logits).unwrap()).collect::<Vec<_>>()
+        };
+        assert_eq!(draw(7), draw(7));
+        assert_ne!(draw(7), draw(8));
+    }
+
+    fn penalised(params: SamplingParams, logits: &[f32], history: &[u32]) -> Vec<f32> {
+        let mut logits = logits.to_vec();
+        sampler(params).apply_penalties(&mut logits, history);
+        logits
+    }
+
+    fn no_penalties() -> SamplingParams {
+        SamplingParams { repetition_penalty: 1.0, frequency_penalty: 0.0, presence_penalty: 0.0, ..plain() }
+    }
+
+    #[test]
+    fn repetition_penalty_pushes_seen_logits_down() {
+        let params = SamplingParams { repetition_penalty: 2.0, ..no_penalties() };
+        assert_eq!(penalised(params, &[2.0, -2.0, 1.0], &[0, 1, 0]), vec![1.0, -4.0, 1.0]);
+    }
+
+    #[test]
+    fn frequency_and_presence_penalties_subtract() {
+        let params = SamplingParams { frequency_penalty: 0.5, presence_penalty: 0.25, ..no_penalties() };
+        assert_eq!(penalised(params, &[1.0, 1.0, 1.0], &[2, 2, 1]), vec![1.0, 0.25, -0.25]);
+    }
+
+    #[test]
+    fn penalties_only_look_at_the_window() {
+        let params = SamplingParams { presence_penalty: 1.0, penalty_last_n: 2, ..no_penalties() };
+        assert_eq!(penalised(params.clone(), &[0.0, 0.0, 0.0], &[0, 1, 2]), vec![0.0, -1.0, -1.0]);
+
+        let disabled = SamplingParams { penalty_last_n: 0, ..params };
+        assert_eq!(penalised(disabled, &[0.0, 0.0, 0.0], &[0, 1, 2]), vec![0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn penalties_skip_ids_outside_the_logits() {
+        let params = SamplingParams { presence_penalty: 1.0, ..no_penalties() };
+        assert_eq!(penalised(params, &[0.0, 0.0], &[1, 7]), vec![0.0, -1.0]);
+    }
+
+    #[test]
+    fn padding_ids_past_the_vocabulary_are_never_picked() {
+        let logits = Tensor::new(&[0.0f32, 1.0, 9.0, 9.0], &candle_core::Device::Cpu).unwrap();
+        let mut sampler = sampler(SamplingParams { temperature: 0.0, ..plain() }).with_vocab_size(2);
+        assert_eq!(sampler.sample(&logits, &[]).unwrap(), 1);
+    }
+
+    #[test]
+    fn call_params_override_the_models_defaults() {
+        let generation_config: GenerationConfig =
+            serde_json::from_str(r#"{"temperature": 0.6, "top_k": 20, "max_new_tokens": 512}"#).unwrap();
+        let defaults = generation_config.sampling_defaults(SamplingParams::default());
+
+        let params = SamplingParams::from_json_with_defaults(r#"{"temperature": 1.2, "stop": ["\n"]}"#, &defaults).unwrap();
+        assert_eq!(params.temperature, 1.2);
+        assert_eq!(params.stop, vec!["\n"]);
+        assert_eq!((params.top_k, params.max_new_tokens), (20, 512));
+        assert_eq!(params.top_p, SamplingParams::default().top_p);
+
+        let params = SamplingParams::from_json_with_defaults("{}", &defaults).unwrap();
+        assert_eq!(params.temperature, 0.6);
+    }
+
+    #[test]
+    fn rejects_malformed_params() {
+        for json in ["[]", "{\"top_k\": -1}", "{\"temperature\": \"hot\"}", "{"] {
+            let result = SamplingParams::from_json_with_defaults(json, &plain());
+            assert!(matches!(result, Err(LlmError::InvalidArgument(_))), "{}", json);
+        }
+    }
+
+    #[test]
+    fn rejects_empty_logits() {
+        assert!(matches!(sampler(plain()).sample_from_slice(&[]), Err(LlmError::Inference(_))));
+    }
+}
@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::path::Path;
+use tokenizers::decoders::byte_fallback::ByteFallback;
+use tokenizers::decoders::fuse::Fuse;
+use tokenizers::decoders::sequence::Sequence as DecoderSequence;
+use tokenizers::decoders::strip::Strip;
+use tokenizers::models::bpe::{Vocab, BPE};
+use tokenizers::models::unigram::Unigram;
+use tokenizers::normalizers::{Prepend, Replace, Sequence as NormalizerSequence};
+use tokenizers::processors::template::TemplateProcessing;
+use tokenizers::{AddedToken, DecoderWrapper, NormalizerWrapper, Tokenizer};
+use crate::error::{LlmError, Result};
+
+/// SentencePiece marks word boundaries with this character instead of a space.
+const SPACE_MARKER: &str = "\u{2581}";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ModelType {
+    Unigram,
+    Bpe,
+    Other(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PieceType {
+    Normal,
+    Unknown,
+    Control,
+    UserDefined,
+    Unused,
+    Byte,
+}
+
+#[derive(Debug, Clone)]
+struct Piece {
+    piece: String,
+    score: f32,
+    kind: PieceType,
+}
+
+/// The parts of a SentencePiece `ModelProto` the conversion needs.
+#[derive(Debug)]
+struct SentencePieceModel {
+    pieces: Vec<Piece>,
+    model_type: ModelType,
+    byte_fallback: bool,
+    unk_id: Option<usize>,
+    bos_id: Option<usize>,
+    add_dummy_prefix: bool,
+}
+
+/// Reads a SentencePiece `tokenizer.model` and builds the equivalent
+/// Hugging Face tokenizer, as `transformers` does for Llama-style models:
+/// BPE or Unigram over `▁`-joined text, byte fallback, and a BOS token in
+/// front. The precompiled normalization rules of some Unigram models are
+/// not applied.
+pub fn convert(path: &Path) -> Result<Tokenizer> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| LlmError::Io(format!("Failed to read {}: {}", path.display(), e)))?;
+    convert_bytes(&bytes)
+}
+
+pub fn convert_bytes(bytes: &[u8]) -> Result<Tokenizer> {
+    let model = SentencePieceModel::parse(bytes)?;
+    if model.pieces.is_empty() {
+        return Err(LlmError::Tokenizer("SentencePiece model has no pieces".to_string()));
+    }
+    let piece_at = |id: Option<usize>| id.and_then(|id| model.pieces.get(id)).map(|p| p.piece.clone());
+    let convert_error = |e: tokenizers::Error| LlmError::Tokenizer(format!("Failed to convert SentencePiece model: {}", e));
+
+    let mut tokenizer = match model.model_type {
+        ModelType::Bpe => {
+            let vocab: Vocab = model.pieces.iter().enumerate().map(|(id, p)| (p.piece.clone(), id as u32)).collect();
+            let mut builder = BPE::builder()
+                .vocab_and_merges(vocab, bpe_merges(&model.pieces))
+                .fuse_unk(true)
+                .byte_fallback(model.byte_fallback);
+            if let Some(unk) = piece_at(model.unk_id) {
+                builder = builder.unk_token(unk);
+            }
+            Tokenizer::new(builder.build().map_err(convert_error)?)
+        }
+        ModelType::Unigram => {
+            let vocab = model.pieces.iter().map(|p| (p.piece.clone(), f64::from(p.score))).collect();
+            Tokenizer::new(Unigram::from(vocab, model.unk_id, model.byte_fallback).map_err(convert_error)?)
+        }
+        ModelType::Other(kind) => {
+            return Err(LlmError::Tokenizer(format!("Unsupported SentencePiece model type {}", kind)));
+        }
+    };
+
+    let replace = |from: &str, to: &str| Replace::new(from, to).map_err(convert_error);
+    let mut normalizers: Vec<NormalizerWrapper> = Vec::new();
+    if model.add_dummy_prefix {
+        normalizers.push(Prepend::new(SPACE_MARKER.to_string()).into());
+    }
+    normalizers.push(replace(" ", SPACE_MARKER)?.into());
+    tokenizer.with_normalizer(Some(NormalizerSequence::new(normalizers)));
+
+    let mut decoders: Vec<DecoderWrapper> = vec![
+        replace(SPACE_MARKER, " ")?.into(),
+        ByteFallback::new().into(),
+        Fuse::new().into(),
+    ];
+    if model.add_dummy_prefix {
+        decoders.push(Strip::new(' ', 1, 0).into());
+    }
+    tokenizer.with_decoder(Some(DecoderSequence::new(decoders)));
+
+    if let (Some(bos_id), Some(bos)) = (model.bos_id, piece_at(model.bos_id)) {
+        let template = TemplateProcessing::builder()
+            .try_single(format!("{} $A", bos))
+            .and_then(|b| b.try_pair(format!("{} $A {} $B:1", bos, bos)))
+            .map_err(|e| LlmError::Tokenizer(format!("Failed to build post processor: {}", e)))?
+            .special_tokens(vec![(bos, bos_id as u32)])
+            .build()
+            .map_err(|e| LlmError::Tokenizer(format!("Failed to build post processor: {}", e)))?;
+        tokenizer.with_post_processor(Some(template));
+    }
+
+    // Control and unknown pieces must never be split or matched as text
+    let special: Vec<AddedToken> = model
+        .pieces
+        .iter()
+        .filter(|p| matches!(p.kind, PieceType::Control | PieceType::Unknown))
+        .map(|p| AddedToken::from(p.piece.clone(), true))
+        .collect();
+    tokenizer.add_special_tokens(&special);
+    let user_defined: Vec<AddedToken> = model
+        .pieces
+        .iter()
+        .filter(|p| p.kind == PieceType::UserDefined)
+        .map(|p| AddedToken::from(p.piece.clone(), false).normalized(false))
+        .collect();
+    tokenizer.add_tokens(&user_defined);
+
+    Ok(tokenizer)
+}
+
+/// Recovers BPE merges from the vocabulary, like `transformers`'
+/// `SentencePieceExtractor`: every split of a piece into two known pieces is
+/// a merge, ranked by the merged piece's score.
+fn bpe_merges(pieces: &[Piece]) -> Vec<(String, String)> {
+    let ids: HashMap<&str, usize> = pieces.iter().enumerate().map(|(id, p)| (p.piece.as_str(), id)).collect();
+
+    let mut merges = Vec::new();
+    for piece in pieces {
+        let mut local: Vec<(usize, usize, f32)> = piece
+            .piece
+            .char_indices()
+            .skip(1)
+            .filter_map(|(split, _)| {
+                let left = ids.get(&piece.piece[..split])?;
+                let right = ids.get(&piece.piece[split..])?;
+                Some((*left, *right, piece.score))
+            })
+            .collect();
+        local.sort_by_key(|&(left, right, _)| (left, right));
+        merges.extend(local);
+    }
+    // Stable, so equal scores keep the order above
+    merges.sort_by(|a, b| b.2.total_cmp(&a.2));
+    merges
+        .into_iter()
+        .map(|(left, right, _)| (pieces[left].piece.clone(), pieces[right].piece.clone()))
+        .collect()
+}
+
+impl SentencePieceModel {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut model = SentencePieceModel {
+            pieces: Vec::new(),
+            model_type: ModelType::Unigram,
+            byte_fallback: false,
+            unk_id: Some(0),
+            bos_id: Some(1),
+            add_dummy_prefix: true,
+        };
+
+        let mut reader = Reader::new(bytes);
+        while let Some((field, value)) = reader.next_field()? {
+            match (field, value) {
+                (1, Value::Bytes(piece)) => model.pieces.push(Piece::parse(piece)?),
+                (2, Value::Bytes(trainer_spec)) => model.parse_trainer_spec(trainer_spec)?,
+                (3, Value::Bytes(normalizer_spec)) => {
+                    let mut reader = Reader::new(normalizer_spec);
+                    while let Some((field, value)) = reader.next_field()? {
+                        if let (3, Value::Varint(v)) = (field, value) {
+                            model.add_dummy_prefix = v != 0;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(model)
+    }
+
+    fn parse_trainer_spec(&mut self, bytes: &[u8]) -> Result<()> {
+        // Negative ids (e.g. pad_id = -1) mean the token is disabled
+        let id = |v: u64| usize::try_from(v as i64 as i32).ok();
+        let mut reader = Reader::new(bytes);
+        while let Some((field, value)) = reader.next_field()? {
+            match (field, value) {
+                (3, Value::Varint(1)) => self.model_type = ModelType::Unigram,
+                (3, Value::Varint(2)) => self.model_type = ModelType::Bpe,
+                (3, Value::Varint(v)) => self.model_type = ModelType::Other(v),
+                (35, Value::Varint(v)) => self.byte_fallback = v != 0,
+                (40, Value::Varint(v)) => self.unk_id = id(v),
+                (41, Value::Varint(v)) => self.bos_id = id(v),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Piece {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut piece = Piece { piece: String::new(), score: 0.0, kind: PieceType::Normal };
+        let mut reader = Reader::new(bytes);
+        while let Some((field, value)) = reader.next_field()? {
+            match (field, value) {
+                (1, Value::Bytes(text)) => {
+                    piece.piece = String::from_utf8(text.to_vec())
+                        .map_err(|_| LlmError::Tokenizer("SentencePiece piece is not valid UTF-8".to_string()))?;
+                }
+                (2, Value::Fixed32(bits)) => piece.score = f32::from_bits(bits),
+                (3, Value::Varint(kind)) => {
+                    piece.kind = match kind {
+                        2 => PieceType::Unknown,
+                        3 => PieceType::Control,
+                        4 => PieceType::UserDefined,
+                        5 => PieceType::Unused,
+                        6 => PieceType::Byte,
+                        _ => PieceType::Normal,
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(piece)
+    }
+}
+
+/// A protobuf field value, by wire type.
+enum Value<'a> {
+    Varint(u64),
+    Fixed64,
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+/// Minimal protobuf wire format reader; enough for `sentencepiece_model.proto`.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, pos: 0 }
+    }
+
+    fn next_field(&mut self) -> Result<Option<(u64, Value<'a>)>> {
+        if self.pos == self.bytes.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 7 {
+            0 => Value::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                Value::Fixed64
+            }
+            2 => {
+                let len = usize::try_from(self.varint()?).map_err(|_| malformed())?;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => Value::Fixed32(u32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+            _ => return Err(malformed()),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.bytes.get(self.pos).ok_or_else(malformed)?;
+            self.pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(malformed())
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.bytes.len()).ok_or_else(malformed)?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+}
+
+fn malformed() -> LlmError {
+    LlmError::Tokenizer("Malformed SentencePiece model".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(out: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn bytes_field(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+        varint(out, field << 3 | 2);
+        varint(out, bytes.len() as u64);
+        out.extend_from_slice(bytes);
+    }
+
+    fn varint_field(out: &mut Vec<u8>, field: u64, value: u64) {
+        varint(out, field << 3);
+        varint(out, value);
+    }
+
+    /// Encodes a `ModelProto` with `pieces` as (piece, score, type).
+    fn model_proto(pieces: &[(&str, f32, u64)], model_type: u64, byte_fallback: bool) -> Vec<u8> {
+        let mut proto = Vec::new();
+        for &(text, score, kind) in pieces {
+            let mut piece = Vec::new();
+            bytes_field(&mut piece, 1, text.as_bytes());
+            varint(&mut piece, 2 << 3 | 5);
+            piece.extend_from_slice(&score.to_le_bytes());
+            varint_field(&mut piece, 3, kind);
+            bytes_field(&mut proto, 1, &piece);
+        }
+        let mut trainer_spec = Vec::new();
+        varint_field(&mut trainer_spec, 3, model_type);
+        varint_field(&mut trainer_spec, 35, byte_fallback as u64);
+        varint_field(&mut trainer_spec, 43, -1i64 as u64);
+        bytes_field(&mut proto, 2, &trainer_spec);
+        proto
+    }
+
+    fn llama_like() -> Vec<u8> {
+        model_proto(
+            &[
+                ("<unk>", 0.0, 2),
+                ("<s>", 0.0, 3),
+                ("</s>", 0.0, 3),
+                ("<0xC3>", 0.0, 6),
+                ("<0xA9>", 0.0, 6),
+                ("▁h", -1.0, 1),
+                ("hi", -2.0, 1),
+                ("▁hi", -3.0, 1),
+                ("▁", -4.0, 1),
+                ("h", -5.0, 1),
+                ("i", -6.0, 1),
+            ],
+            2,
+            true,
+        )
+    }
+
+    #[test]
+    fn converts_bpe_models_with_byte_fallback() {
+        let tokenizer = convert_bytes(&llama_like()).unwrap();
+        let encoding = tokenizer.encode("hi hi", true).unwrap();
+        assert_eq!(encoding.get_tokens(), ["<s>", "▁hi", "▁hi"]);
+        assert_eq!(tokenizer.decode(encoding.get_ids(), true).unwrap(), "hi hi");
+
+        let encoding = tokenizer.encode("é", true).unwrap();
+        assert_eq!(encoding.get_tokens(), ["<s>", "▁", "<0xC3>", "<0xA9>"]);
+        assert_eq!(tokenizer.decode(encoding.get_ids(), true).unwrap(), "é");
+
+        // Control pieces are special tokens rather than text
+        assert_eq!(tokenizer.encode("hi</s>", false).unwrap().get_ids(), [7, 2]);
+    }
+
+    #[test]
+    fn converts_unigram_models() {
+        let proto = model_proto(
+            &[("<unk>", 0.0, 2), ("<s>", 0.0, 3), ("</s>", 0.0, 3), ("▁hi", -1.0, 1), ("▁", -3.0, 1), ("h", -3.0, 1), ("i", -3.0, 1)],
+            1,
+            false,
+        );
+        let tokenizer = convert_bytes(&proto).unwrap();
+        assert_eq!(tokenizer.encode("hi", true).unwrap().get_ids(), [1, 3]);
+        assert_eq!(tokenizer.decode(&[1, 3, 3], true).unwrap(), "hi hi");
+    }
+
+    #[test]
+    fn rejects_malformed_models() {
+        assert!(convert_bytes(&[]).is_err());
+        assert!(convert_bytes(&[0x0a, 0x05, 0x0a]).is_err());
+        assert!(convert_bytes(&[0xff; 11]).is_err());
+    }
+
+    #[test]
+    fn load_from_dir_caches_the_conversion() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        std::fs::write(dir.join("tokenizer.model"), llama_like()).unwrap();
+
+        let converted = crate::tokenizer::load_from_dir(dir).unwrap();
+        assert!(dir.join("tokenizer.json").exists());
+        std::fs::remove_file(dir.join("tokenizer.model")).unwrap();
+        let cached = crate::tokenizer::load_from_dir(dir).unwrap();
+        assert_eq!(
+            converted.encode("hi é", true).unwrap().get_ids(),
+            cached.encode("hi é", true).unwrap().get_ids()
+        );
+    }
+}
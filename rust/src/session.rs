@@ -0,0 +1,249 @@
+use serde::Serialize;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokenizers::Tokenizer;
+use crate::chat_template::ChatTemplate;
+use crate::context::ContextOverflow;
+use crate::error::{LlmError, Result};
+use crate::inference::{CancellationToken, GenerationOutput};
+use crate::model::Model;
+use crate::sampler::SamplingParams;
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+impl FromStr for Role {
+    type Err = LlmError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "system" => Ok(Role::System),
+            "user" => Ok(Role::User),
+            "assistant" => Ok(Role::Assistant),
+            other => Err(LlmError::InvalidArgument(format!("Unknown role: {}", other))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+/// A conversation with the loaded model.
+///
+/// The session remembers every token it has fed to the model. As long as the
+/// model's KV cache still belongs to this session, a new turn only prefills
+/// the tokens added since the last reply; otherwise the whole history is
+/// replayed once. Models that can only append one token per pass (Phi, GGUF
+/// and Llamas with rope scaling or tied embeddings) also replay the history
+/// when that is cheaper than a pass per new token.
+pub struct ChatSession {
+    id: u64,
+    messages: Vec<ChatMessage>,
+    /// Rendered chat template text that `tokens` corresponds to, or `None`
+    /// if the two went out of sync and the history must be re-encoded.
+    rendered: Option<String>,
+    /// Prompt and reply tokens of the conversation so far.
+    tokens: Vec<u32>,
+    /// `Model::id` of the model that encoded `tokens`.
+    model_id: Option<u64>,
+}
+
+impl ChatSession {
+    pub fn new(system_prompt: Option<&str>) -> Self {
+        let mut session = ChatSession {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            messages: Vec::new(),
+            rendered: Some(String::new()),
+            tokens: Vec::new(),
+            model_id: None,
+        };
+        if let Some(prompt) = system_prompt {
+            session.append(Role::System, prompt);
+        }
+        session
+    }
+
+    pub fn append(&mut self, role: Role, content: &str) {
+        self.messages.push(ChatMessage {
+            role,
+            content: content.to_string(),
+        });
+    }
+
+    /// Generates the assistant's reply to the messages appended so far and
+    /// adds it to the conversation.
+    pub fn generate(
+        &mut self,
+        model: &mut Model,
+        params: &SamplingParams,
+        cancel: &CancellationToken,
+        on_text: &mut dyn FnMut(&str),
+    ) -> Result<GenerationOutput> {
+        // Another model's ids mean nothing to this one, even if its template
+        // renders the same text, and this model's cache may still hold an
+        // older turn of ours. Out of sync, the history is re-encoded and the
+        // cache reset below
+        if self.model_id != Some(model.id) {
+            self.tokens.clear();
+            self.rendered = None;
+            self.model_id = Some(model.id);
+        }
+
+        let prompt = model.chat_template.render(&self.messages, true)?;
+
+        // Templates only ever append to the conversation, so normally just
+        // the text after what was already fed needs encoding
+        let delta = self.rendered.as_deref().and_then(|done| prompt.strip_prefix(done));
+        let in_sync = delta.is_some();
+        let delta = match delta {
+            Some(delta) => delta.to_string(),
+            None => {
+                self.tokens.clear();
+                prompt.clone()
+            }
+        };
+        self.tokens.extend(encode(&model.tokenizer, &delta)?);
+        self.rendered = Some(prompt.clone());
+
+        // A truncated history no longer matches the rendered text, so it is
+        // rebuilt (and truncated again) every turn from here on. Whole turns
+        // go first, so no cut lands inside the template's role markers
+        let budget = model.prompt_budget(params);
+        let fitted = if self.tokens.len() > budget && params.context_overflow != ContextOverflow::Error {
+            self.drop_oldest_turns(&model.chat_template, &model.tokenizer, budget)
+                .and_then(|tokens| model.fit_to_context(&tokens, params))
+        } else {
+            model.fit_to_context(&self.tokens, params)
+        };
+        let truncated = match fitted {
+            Ok(fitted) if fitted.len() == self.tokens.len() => false,
+            Ok(fitted) => {
+                self.tokens = fitted;
+                true
+            }
+            Err(e) => {
+                self.rendered = None;
+                return Err(e);
+            }
+        };
+
+        // Reuse the cache if it still holds a prefix of this conversation
+        // and the rest can be appended without a pass per token
+        let start = if in_sync
+            && !truncated
+            && model.cache_owner == Some(self.id)
+            && model.cache_len() <= self.tokens.len()
+            && model.can_append(self.tokens.len() - model.cache_len())
+        {
+            model.cache_len()
+        } else {
+            model.reset_cache()?;
+            0
+        };
+        println!("Session {}: prefilling {} of {} tokens", self.id, self.tokens.len() - start, self.tokens.len());
+
+        model.cache_owner = Some(self.id);
+        let to_feed = self.tokens[start..].to_vec();
+        let output = model.generate_from_tokens(&to_feed, params, cancel, on_text)?;
+
+        self.tokens.extend_from_slice(&output.tokens);
+        let reply = output.text.trim().to_string();
+        self.messages.push(ChatMessage {
+            role: Role::Assistant,
+            content: reply.clone(),
+        });
+
+        // The template may close the reply with tokens the model never fed
+        // (e.g. an end-of-turn marker); queue those for the next turn
+        let after = model.chat_template.render(&self.messages, false)?;
+        let tail = after
+            .strip_prefix(prompt.as_str())
+            .and_then(|turn| turn.trim_start().strip_prefix(reply.as_str()))
+            .map(str::to_string);
+        self.rendered = match tail {
+            // After a trimmed stop sequence or a truncation the tokens no
+            // longer match the rendered text, so the history is re-encoded
+            _ if output.stop_sequence.is_some() || truncated => None,
+            Some(tail) => {
+                self.tokens.extend(encode(&model.tokenizer, &tail)?);
+                Some(after)
+            }
+            None => None,
+        };
+
+        Ok(output)
+    }
+
+    /// Renders the prompt without the oldest turns, one more each time,
+    /// until it fits in `budget` tokens. Leading system messages and the
+    /// latest turn are always kept, so the result may still be too long.
+    fn drop_oldest_turns(&self, template: &ChatTemplate, tokenizer: &Tokenizer, budget: usize) -> Result<Vec<u32>> {
+        let system = self.messages.iter().take_while(|m| m.role == Role::System).count();
+        let last_turn = self
+            .messages
+            .iter()
+            .rposition(|m| m.role == Role::Assistant)
+            .map_or(system, |i| i + 1);
+
+        let mut tokens = self.tokens.clone();
+        // Keep roles alternating by only ever starting the kept part at a user message
+        for start in (system + 1..=last_turn).filter(|&i| self.messages.get(i).is_some_and(|m| m.role == Role::User)) {
+            let kept: Vec<ChatMessage> = self.messages[..system].iter().chain(&self.messages[start..]).cloned().collect();
+            tokens = encode(tokenizer, &template.render(&kept, true)?)?;
+            if tokens.len() <= budget {
+                break;
+            }
+        }
+        Ok(tokens)
+    }
+}
+
+fn encode(tokenizer: &Tokenizer, text: &str) -> Result<Vec<u32>> {
+    // The template already places BOS and other special tokens
+    let encoding = tokenizer.encode(text, false)
+        .map_err(|e| LlmError::Tokenizer(format!("Failed to tokenize: {}", e)))?;
+    Ok(encoding.get_ids().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::tests::word_level;
+
+    #[test]
+    fn drops_whole_turns_but_keeps_the_system_prompt_and_latest_turn() {
+        let template = ChatTemplate::new("{% for m in messages %}{{ m.role }} {{ m.content }} {% endfor %}", "<s>", "</s>").unwrap();
+        let tokenizer = word_level();
+        let mut session = ChatSession::new(Some("the"));
+        for (role, content) in [
+            (Role::User, "hello"),
+            (Role::Assistant, "world"),
+            (Role::User, "cat cat"),
+            (Role::Assistant, "world"),
+            (Role::User, "hello"),
+            (Role::User, "!"),
+        ] {
+            session.append(role, content);
+        }
+        let text = |tokens: &[u32]| tokenizer.decode(tokens, false).unwrap();
+
+        // Every message is a role word plus its content
+        let tokens = session.drop_oldest_turns(&template, &tokenizer, 11).unwrap();
+        assert_eq!(text(&tokens), "<unk> the <unk> cat cat <unk> world <unk> hello <unk> !");
+        let tokens = session.drop_oldest_turns(&template, &tokenizer, 8).unwrap();
+        assert_eq!(text(&tokens), "<unk> the <unk> hello <unk> !");
+        // The system prompt and the latest turn stay even if they do not fit
+        let tokens = session.drop_oldest_turns(&template, &tokenizer, 2).unwrap();
+        assert_eq!(text(&tokens), "<unk> the <unk> hello <unk> !");
+    }
+}
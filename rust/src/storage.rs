@@ -0,0 +1,234 @@
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+use crate::error::{LlmError, Result};
+use crate::lock;
+
+/// Environment variable that sets the storage root when the host app has
+/// not called `set_storage_root_c`. Meant for desktop use and tests.
+pub const HOME_ENV_VAR: &str = "LLM_RUNNER_HOME";
+
+lazy_static! {
+    // Set by the host app, e.g. to its documents directory on iOS/Android
+    static ref STORAGE_ROOT: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// Makes `root` the directory downloaded models are stored under, creating
+/// it if needed. `None` goes back to `LLM_RUNNER_HOME` or the working
+/// directory.
+pub fn set_root(root: Option<&Path>) -> Result<()> {
+    if let Some(root) = root {
+        std::fs::create_dir_all(root)
+            .map_err(|e| LlmError::Io(format!("Failed to create storage root {}: {}", root.display(), e)))?;
+    }
+    *lock(&STORAGE_ROOT) = root.map(Path::to_path_buf);
+    Ok(())
+}
+
+/// The root set by the app or `LLM_RUNNER_HOME`, if any.
+pub fn configured_root() -> Option<PathBuf> {
+    lock(&STORAGE_ROOT)
+        .clone()
+        .or_else(|| std::env::var_os(HOME_ENV_VAR).filter(|home| !home.is_empty()).map(PathBuf::from))
+}
+
+/// Directory holding one subdirectory per model: `<root>/models`, or
+/// `models` in the working directory when no root is configured.
+pub fn models_dir() -> PathBuf {
+    configured_root().unwrap_or_default().join("models")
+}
+
+/// Where hf-hub keeps its download cache. `None` leaves it at hf-hub's
+/// default (`HF_HOME` or `~/.cache/huggingface`), which is only used when
+/// no root is configured. Downloads are hard-linked from here into the
+/// model directories, so each file takes up space once.
+pub fn hub_cache_dir() -> Option<PathBuf> {
+    configured_root().map(|root| root.join("hub-cache"))
+}
+
+/// Hugging Face's limit on the length of a repo name.
+const MAX_NAME_LEN: usize = 96;
+
+/// Local directory of `model_id`, created if it does not exist yet.
+pub fn model_dir(model_id: &str) -> Result<PathBuf> {
+    model_dir_in(&models_dir(), model_id)
+}
+
+fn model_dir_in(models_dir: &Path, model_id: &str) -> Result<PathBuf> {
+    validate_model_id(model_id)?;
+    std::fs::create_dir_all(models_dir)
+        .map_err(|e| LlmError::Io(format!("Failed to create models directory: {}", e)))?;
+    let root = canonical(models_dir)?;
+    let inside_root = |path: &Path| -> Result<()> {
+        if canonical(path)?.starts_with(&root) {
+            Ok(())
+        } else {
+            Err(LlmError::InvalidModelId(format!("{} resolves outside the storage root", model_id)))
+        }
+    };
+
+    // A symlink inside the storage root could still point elsewhere, so
+    // check what already exists before creating anything below it
+    let model_dir = models_dir.join(model_id);
+    if let Some(existing) = model_dir.ancestors().find(|path| path.exists()) {
+        inside_root(existing)?;
+    }
+    std::fs::create_dir_all(&model_dir)
+        .map_err(|e| LlmError::Io(format!("Failed to create model directory: {}", e)))?;
+    inside_root(&model_dir)?;
+    canonical(&model_dir)
+}
+
+fn canonical(path: &Path) -> Result<PathBuf> {
+    path.canonicalize()
+        .map_err(|e| LlmError::Io(format!("Failed to resolve {}: {}", path.display(), e)))
+}
+
+/// Checks `model_id` against the Hub's repo id grammar: `name` or
+/// `org/name`, where each part is at most 96 of `A-Z a-z 0-9 - _ .`,
+/// does not start or end with `-` or `.`, and contains no `--` or `..`.
+pub fn validate_model_id(model_id: &str) -> Result<()> {
+    let invalid = |reason: &str| Err(LlmError::InvalidModelId(format!("{:?} {}", model_id, reason)));
+
+    let parts: Vec<&str> = model_id.split('/').collect();
+    if parts.len() > 2 {
+        return invalid("must be \"name\" or \"org/name\"");
+    }
+    for part in parts {
+        if part.is_empty() {
+            return invalid("has an empty part");
+        }
+        if part.len() > MAX_NAME_LEN {
+            return invalid("is too long");
+        }
+        if !part.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+            return invalid("may only contain letters, digits, '-', '_' and '.'");
+        }
+        if part.starts_with(['-', '.']) || part.ends_with(['-', '.']) {
+            return invalid("may not start or end with '-' or '.'");
+        }
+        if part.contains("--") || part.contains("..") {
+            return invalid("may not contain '--' or '..'");
+        }
+    }
+    Ok(())
+}
+
+/// Checks a file name from a repo (a GGUF file or a weight shard listed in
+/// an index) before it is joined onto a model directory. Subdirectories are
+/// allowed, but not absolute paths or `..`.
+pub fn validate_file_name(filename: &str) -> Result<()> {
+    let path = Path::new(filename);
+    let relative = !filename.is_empty()
+        && !filename.contains(['\\', '\0'])
+        && path.components().all(|c| matches!(c, Component::Normal(_)));
+    if !relative {
+        return Err(LlmError::InvalidModelId(format!("{:?} is not a relative file name inside the repo", filename)));
+    }
+    Ok(())
+}
+
+/// Puts the file at `from`, e.g. in the hub cache, at `to` without storing
+/// it twice: `from` is resolved through symlinks and hard-linked. Files on
+/// another filesystem are copied.
+pub fn link_or_copy(from: &Path, to: &Path) -> Result<()> {
+    let source = canonical(from)?;
+    if std::fs::hard_link(&source, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(&source, to)
+        .map(|_| ())
+        .map_err(|e| LlmError::Io(format!("Failed to copy {} to {}: {}", source.display(), to.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_hub_ids() {
+        for id in ["gpt2", "TinyLlama/TinyLlama-1.1B-Chat-v1.0", "Qwen/Qwen2.5-0.5B-Instruct", "microsoft/phi-2", "a_b/c.d"] {
+            assert!(validate_model_id(id).is_ok(), "{}", id);
+        }
+    }
+
+    #[test]
+    fn rejects_traversal_and_malformed_ids() {
+        let long = "a".repeat(MAX_NAME_LEN + 1);
+        for id in [
+            "",
+            ".",
+            "..",
+            "../../etc",
+            "org/../../etc",
+            "org/..",
+            "/etc/passwd",
+            "org//name",
+            "org/name/",
+            "org/name/extra",
+            "..\\..\\windows",
+            "C:\\models",
+            "org/.hidden",
+            "org/name-",
+            "org/na--me",
+            "org/name\0",
+            "org/na me",
+            "~/models",
+            long.as_str(),
+        ] {
+            let err = validate_model_id(id).unwrap_err();
+            assert!(matches!(err, LlmError::InvalidModelId(_)), "{:?}", id);
+            assert_eq!(err.status(), crate::error::LlmStatus::InvalidModelId);
+        }
+    }
+
+    #[test]
+    fn rejects_file_names_leaving_the_repo() {
+        assert!(validate_file_name("model.Q4_K_M.gguf").is_ok());
+        assert!(validate_file_name("q4/model-00001-of-00002.safetensors").is_ok());
+        for name in ["", "../model.gguf", "a/../../b", "/etc/passwd", "./model.gguf", "a\\..\\b", "model\0.gguf"] {
+            assert!(matches!(validate_file_name(name), Err(LlmError::InvalidModelId(_))), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn model_dir_stays_inside_the_root() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        let models_dir = root.join("models");
+        std::fs::create_dir_all(&models_dir).unwrap();
+
+        let dir = model_dir_in(&models_dir, "org/name").unwrap();
+        assert!(dir.ends_with("models/org/name"));
+        assert!(model_dir_in(&models_dir, "../escape").is_err());
+        assert!(!root.join("escape").exists());
+
+        #[cfg(unix)]
+        {
+            let outside = root.join("outside");
+            std::fs::create_dir_all(&outside).unwrap();
+            std::os::unix::fs::symlink(&outside, models_dir.join("linked")).unwrap();
+            let err = model_dir_in(&models_dir, "linked/name").unwrap_err();
+            assert!(matches!(err, LlmError::InvalidModelId(_)));
+            assert!(!outside.join("name").exists());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_files_out_of_the_cache() {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        std::fs::create_dir_all(root.join("blobs")).unwrap();
+        std::fs::write(root.join("blobs/abc"), "weights").unwrap();
+        std::os::unix::fs::symlink(root.join("blobs/abc"), root.join("snapshot")).unwrap();
+
+        link_or_copy(&root.join("snapshot"), &root.join("model.safetensors")).unwrap();
+        let linked = std::fs::symlink_metadata(root.join("model.safetensors")).unwrap();
+        assert!(linked.is_file());
+        assert_eq!(linked.ino(), std::fs::metadata(root.join("blobs/abc")).unwrap().ino());
+        assert!(link_or_copy(&root.join("missing"), &root.join("other")).is_err());
+    }
+}
@@ -1,34 +1,310 @@
+use serde::Serialize;
+use std::path::Path;
 use tokenizers::Tokenizer;
-use std::ffi::{c_char, c_void};
+use crate::error::{LlmError, Result};
+use crate::sentencepiece;
 
-pub fn tokenize(text: &str) -> Result<Vec<u32>, String> {
-    let tokenizer = Tokenizer::from_file("path/to/your/tokenizer.json")
-        .map_err(|e| format!("Tokenizer error: {:?}", e))?;
+pub const TOKENIZER_FILE: &str = "tokenizer.json";
+/// SentencePiece model shipped instead of `tokenizer.json` by some repos.
+pub const SENTENCEPIECE_FILE: &str = "tokenizer.model";
 
-    let encoding = tokenizer.encode(text, true)
-        .map_err(|e| format!("Encoding error: {:?}", e))?;
+/// Loads a Hugging Face `tokenizer.json`.
+pub fn load(path: &Path) -> Result<Tokenizer> {
+    Tokenizer::from_file(path)
+        .map_err(|e| LlmError::Tokenizer(format!("Failed to load tokenizer: {}", e)))
+}
+
+/// Loads the tokenizer in `model_dir`. Without a `tokenizer.json` the
+/// SentencePiece `tokenizer.model` is converted, and the result is saved as
+/// `tokenizer.json` so later loads skip the conversion.
+pub fn load_from_dir(model_dir: &Path) -> Result<Tokenizer> {
+    let tokenizer_path = model_dir.join(TOKENIZER_FILE);
+    let sentencepiece_path = model_dir.join(SENTENCEPIECE_FILE);
+    if tokenizer_path.exists() || !sentencepiece_path.exists() {
+        return load(&tokenizer_path);
+    }
 
+    println!("Converting {} to {}", sentencepiece_path.display(), TOKENIZER_FILE);
+    let tokenizer = sentencepiece::convert(&sentencepiece_path)?;
+    if let Err(e) = tokenizer.save(&tokenizer_path, false) {
+        println!("Failed to cache converted tokenizer: {}", e);
+    }
+    Ok(tokenizer)
+}
+
+/// Token ids of `text`. With `add_special_tokens` the tokenizer's post
+/// processor adds its markers, e.g. a leading BOS token.
+pub fn tokenize(tokenizer: &Tokenizer, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+    let encoding = tokenizer.encode(text, add_special_tokens)
+        .map_err(|e| LlmError::Tokenizer(format!("Failed to tokenize: {}", e)))?;
     Ok(encoding.get_ids().to_vec())
 }
 
-pub fn detokenize(tokens: &[u32]) -> Result<String, String> {
-    let tokenizer = Tokenizer::from_file("path/to/your/tokenizer.json")
-        .map_err(|e| format!("Tokenizer error: {:?}", e))?;
+/// Text of `ids`, leaving out special tokens if `skip_special_tokens`.
+pub fn detokenize(tokenizer: &Tokenizer, ids: &[u32], skip_special_tokens: bool) -> Result<String> {
+    if let Some(id) = ids.iter().find(|&&id| tokenizer.id_to_token(id).is_none()) {
+        return Err(LlmError::InvalidArgument(format!("Token id {} is not in the vocabulary", id)));
+    }
+    tokenizer.decode(ids, skip_special_tokens)
+        .map_err(|e| LlmError::Tokenizer(format!("Failed to decode: {}", e)))
+}
+
+/// One past the largest id the tokenizer knows, added tokens included.
+/// `get_vocab_size` counts tokens instead, which falls short when added
+/// tokens leave gaps in the ids.
+pub fn id_bound(tokenizer: &Tokenizer) -> usize {
+    tokenizer.get_vocab(true).into_values().max().map_or(0, |id| id as usize + 1)
+}
+
+/// Number of tokens `text` takes up, special tokens included.
+pub fn count_tokens(tokenizer: &Tokenizer, text: &str) -> Result<usize> {
+    tokenize(tokenizer, text, true).map(|ids| ids.len())
+}
+
+/// One token of an encoded text and where it came from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TokenSpan {
+    pub id: u32,
+    /// The token as it appears in the vocabulary, e.g. `▁hello` or `Ġworld`.
+    pub piece: String,
+    /// Byte range of the token in the input text.
+    pub byte_start: usize,
+    pub byte_end: usize,
+    /// The same range in characters (Unicode scalar values).
+    pub char_start: usize,
+    pub char_end: usize,
+    /// Whether this is a special token such as BOS. Tokens added by the post
+    /// processor have an empty range at 0.
+    pub special: bool,
+}
+
+/// Tokenizes `text` and maps every token back to its range in `text`.
+pub fn token_spans(tokenizer: &Tokenizer, text: &str, add_special_tokens: bool) -> Result<Vec<TokenSpan>> {
+    let encoding = tokenizer.encode(text, add_special_tokens)
+        .map_err(|e| LlmError::Tokenizer(format!("Failed to tokenize: {}", e)))?;
+    let added_tokens = tokenizer.get_added_tokens_decoder();
 
-    let text = tokenizer.decode(tokens, true)
-        .map_err(|e| format!("Decoding error: {:?}", e))?;
-    
-    Ok(text)
+    // A byte-level token can end inside a multi-byte character; such a
+    // character counts as covered
+    let char_starts: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+    let char_offset = |byte: usize| char_starts.partition_point(|&start| start < byte);
+
+    let spans = encoding
+        .get_ids()
+        .iter()
+        .zip(encoding.get_tokens())
+        .zip(encoding.get_offsets())
+        .zip(encoding.get_special_tokens_mask())
+        .map(|(((&id, piece), &(byte_start, byte_end)), &mask)| TokenSpan {
+            id,
+            piece: piece.clone(),
+            byte_start,
+            byte_end,
+            char_start: char_offset(byte_start),
+            char_end: char_offset(byte_end),
+            special: mask == 1 || added_tokens.get(&id).is_some_and(|token| token.special),
+        })
+        .collect();
+    Ok(spans)
 }
 
-#[no_mangle]
-pub extern "C" fn tokenize_text(_text: *const c_char) -> *mut c_void {
-    // Implementation will go here
-    todo!()
+/// Turns generated tokens into text as they arrive.
+///
+/// Decoding each token on its own splits characters that span several byte
+/// tokens (emoji, CJK) and drops the space SentencePiece puts in front of a
+/// piece like `▁world`. Instead, the newest tokens are decoded together with
+/// the previous ones for context, and only the text they add is returned,
+/// once it ends in a complete character.
+pub struct IncrementalDecoder<'a> {
+    tokenizer: &'a Tokenizer,
+    skip_special_tokens: bool,
+    ids: Vec<u32>,
+    /// Start of the tokens decoded as context for the pending ones.
+    prefix_offset: usize,
+    /// Tokens before this index have been returned as text.
+    read_offset: usize,
+}
+
+impl<'a> IncrementalDecoder<'a> {
+    pub fn new(tokenizer: &'a Tokenizer, skip_special_tokens: bool) -> Self {
+        IncrementalDecoder {
+            tokenizer,
+            skip_special_tokens,
+            ids: Vec::new(),
+            prefix_offset: 0,
+            read_offset: 0,
+        }
+    }
+
+    /// Adds `id` and returns the text it completes, which is empty while a
+    /// character is still missing bytes.
+    pub fn push(&mut self, id: u32) -> Result<String> {
+        self.ids.push(id);
+        let (prefix, text) = self.decode_pending()?;
+        if text.len() <= prefix.len() || text.ends_with('\u{FFFD}') {
+            return Ok(String::new());
+        }
+        self.prefix_offset = self.read_offset;
+        self.read_offset = self.ids.len();
+        Ok(delta(&prefix, &text))
+    }
+
+    /// Returns whatever is still pending at the end of the output. Bytes
+    /// that never formed a character come out as U+FFFD, as in a full decode.
+    pub fn flush(&mut self) -> Result<String> {
+        let (prefix, text) = self.decode_pending()?;
+        self.prefix_offset = self.ids.len();
+        self.read_offset = self.ids.len();
+        Ok(if text.len() > prefix.len() { delta(&prefix, &text) } else { String::new() })
+    }
+
+    /// Decodes the context tokens with and without the pending ones.
+    fn decode_pending(&self) -> Result<(String, String)> {
+        let prefix = detokenize(self.tokenizer, &self.ids[self.prefix_offset..self.read_offset], self.skip_special_tokens)?;
+        let text = detokenize(self.tokenizer, &self.ids[self.prefix_offset..], self.skip_special_tokens)?;
+        Ok((prefix, text))
+    }
 }
 
-#[no_mangle]
-pub extern "C" fn detokenize_ids(_tokens: *const u32, _length: usize) -> *mut c_char {
-    // Implementation will go here
-    todo!()
-}
\ No newline at end of file
+fn delta(prefix: &str, text: &str) -> String {
+    match text.strip_prefix(prefix) {
+        Some(delta) => delta.to_string(),
+        // The decoder rewrote the context (e.g. stripped a space); fall back
+        // to the part past its old length
+        None => text.chars().skip(prefix.chars().count()).collect(),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::sampler::{Sampler, SamplingParams};
+
+    /// Word-level tokenizer with `<unk>`, `<s>` and `</s>`; the post
+    /// processor puts `<s>` in front.
+    pub fn word_level() -> Tokenizer {
+        fixture("word_level_tokenizer.json")
+    }
+
+    fn fixture(name: &str) -> Tokenizer {
+        load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)).unwrap()
+    }
+
+    /// Pushes `ids` one by one and returns the text deltas.
+    fn stream(tokenizer: &Tokenizer, ids: &[u32]) -> Vec<String> {
+        let mut decoder = IncrementalDecoder::new(tokenizer, true);
+        let mut deltas: Vec<String> = ids.iter().map(|&id| decoder.push(id).unwrap()).collect();
+        deltas.push(decoder.flush().unwrap());
+        deltas
+    }
+
+    #[test]
+    fn tokenizes_with_and_without_special_tokens() {
+        let tokenizer = word_level();
+        assert_eq!(tokenize(&tokenizer, "hello world", true).unwrap(), vec![1, 3, 4]);
+        assert_eq!(tokenize(&tokenizer, "hello world", false).unwrap(), vec![3, 4]);
+        assert_eq!(tokenize(&tokenizer, "hello mars", false).unwrap(), vec![3, 0]);
+    }
+
+    #[test]
+    fn detokenizes() {
+        let tokenizer = word_level();
+        assert_eq!(detokenize(&tokenizer, &[1, 3, 4, 2], true).unwrap(), "hello world");
+        assert_eq!(detokenize(&tokenizer, &[1, 3, 4, 2], false).unwrap(), "<s> hello world </s>");
+        assert_eq!(detokenize(&tokenizer, &[], true).unwrap(), "");
+    }
+
+    #[test]
+    fn bounds_ids_past_gaps_left_by_added_tokens() {
+        // Three tokens, but `<|end|>` sits at id 5
+        let tokenizer: Tokenizer = r#"{
+            "added_tokens": [{"id": 5, "content": "<|end|>", "single_word": false, "lstrip": false,
+                              "rstrip": false, "normalized": false, "special": true}],
+            "pre_tokenizer": {"type": "Whitespace"},
+            "model": {"type": "WordLevel", "vocab": {"<unk>": 0, "hi": 1, "<|end|>": 5}, "unk_token": "<unk>"}
+        }"#.parse().unwrap();
+        assert_eq!(tokenizer.get_vocab_size(true), 3);
+        assert_eq!(id_bound(&tokenizer), 6);
+
+        // Logits wider than the vocabulary: padding past it is never picked,
+        // the added token in the gap still is
+        let mut logits = vec![0.0f32; 8];
+        logits[5] = 5.0;
+        logits[7] = 9.0;
+        let logits = candle_core::Tensor::new(logits.as_slice(), &candle_core::Device::Cpu).unwrap();
+        let params = SamplingParams { temperature: 0.0, ..SamplingParams::default() };
+        let mut sampler = Sampler::new(params).with_vocab_size(id_bound(&tokenizer));
+        let id = sampler.sample(&logits, &[]).unwrap();
+        assert_eq!(id, 5);
+        assert_eq!(detokenize(&tokenizer, &[1, id], false).unwrap(), "hi <|end|>");
+        assert!(detokenize(&tokenizer, &[3], false).is_err());
+    }
+
+    #[test]
+    fn rejects_ids_outside_the_vocabulary() {
+        let err = detokenize(&word_level(), &[3, 1000], true).unwrap_err();
+        assert!(matches!(err, LlmError::InvalidArgument(_)));
+    }
+
+    #[test]
+    fn maps_tokens_to_byte_and_char_ranges() {
+        let spans = token_spans(&word_level(), "naïve cat </s>", true).unwrap();
+        let ranges: Vec<_> = spans
+            .iter()
+            .map(|s| (s.id, s.piece.as_str(), s.byte_start, s.byte_end, s.char_start, s.char_end, s.special))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![
+                (1, "<s>", 0, 0, 0, 0, true),
+                (8, "naïve", 0, 6, 0, 5, false),
+                (7, "cat", 7, 10, 6, 9, false),
+                (2, "</s>", 11, 15, 10, 14, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_tokens() {
+        let tokenizer = word_level();
+        assert_eq!(count_tokens(&tokenizer, "hello world !").unwrap(), 4);
+        assert_eq!(count_tokens(&tokenizer, "").unwrap(), 1);
+    }
+
+    #[test]
+    fn sentencepiece_deltas_keep_leading_spaces() {
+        // <s> ▁Hello ▁world ! </s>
+        let tokenizer = fixture("sentencepiece_tokenizer.json");
+        let ids = [1, 7, 8, 9, 2];
+        assert_eq!(stream(&tokenizer, &ids), ["", "Hello", " world", "!", "", ""]);
+        assert_eq!(stream(&tokenizer, &ids).concat(), detokenize(&tokenizer, &ids, true).unwrap());
+    }
+
+    #[test]
+    fn sentencepiece_byte_fallback_waits_for_whole_characters() {
+        // ▁Hello ▁ <0xF0> <0x9F> <0x98> <0x80> ▁你 好
+        let tokenizer = fixture("sentencepiece_tokenizer.json");
+        let ids = [7, 10, 3, 4, 5, 6, 11, 12];
+        assert_eq!(stream(&tokenizer, &ids), ["Hello", " ", "", "", "", "😀", " 你", "好", ""]);
+        assert_eq!(stream(&tokenizer, &ids).concat(), "Hello 😀 你好");
+    }
+
+    #[test]
+    fn byte_level_deltas_wait_for_whole_characters() {
+        // Hello Ġworld ! Ġð Łĺ Ģ Ġä½ ł <|endoftext|>
+        let tokenizer = fixture("byte_level_tokenizer.json");
+        let ids = [1, 2, 3, 4, 5, 6, 7, 8, 0];
+        assert_eq!(stream(&tokenizer, &ids), ["Hello", " world", "!", "", "", " 😀", "", " 你", "", ""]);
+        assert_eq!(stream(&tokenizer, &ids).concat(), detokenize(&tokenizer, &ids, true).unwrap());
+    }
+
+    #[test]
+    fn flush_returns_incomplete_bytes() {
+        let tokenizer = fixture("byte_level_tokenizer.json");
+        let mut decoder = IncrementalDecoder::new(&tokenizer, true);
+        assert_eq!(decoder.push(1).unwrap(), "Hello");
+        assert_eq!(decoder.push(4).unwrap(), "");
+        assert_eq!(decoder.flush().unwrap(), " \u{FFFD}");
+        assert_eq!(decoder.flush().unwrap(), "");
+    }
+}
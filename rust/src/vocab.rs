@@ -0,0 +1,177 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::path::Path;
+use tokenizers::Tokenizer;
+use crate::chat_template;
+use crate::error::{LlmError, Result};
+
+/// Ids of the tokens with a special role, `None` when the model has no
+/// such token. Resolved once at load time.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SpecialTokens {
+    pub bos_token_id: Option<u32>,
+    /// The tokenizer's end-of-sequence token. Generation may stop on more
+    /// ids than this one, see `Model::eos_token_ids`.
+    pub eos_token_id: Option<u32>,
+    pub pad_token_id: Option<u32>,
+    pub unk_token_id: Option<u32>,
+}
+
+impl SpecialTokens {
+    /// Looks up the special tokens named in `tokenizer_config.json`, see
+    /// `from_config`. A missing or unreadable file names none.
+    pub fn resolve(tokenizer: &Tokenizer, tokenizer_config_path: &Path, eos_token_ids: &[u32]) -> Self {
+        let config: Value = std::fs::read_to_string(tokenizer_config_path)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or(Value::Null);
+        Self::from_config(tokenizer, &config, eos_token_ids)
+    }
+
+    /// Looks up the `bos_token`, `eos_token`, `pad_token` and `unk_token`
+    /// of a parsed `tokenizer_config.json`. Tokens it leaves out fall back
+    /// to the tokenizer's padding settings, the first of `eos_token_ids`,
+    /// or the usual `<s>`, `<pad>` and `<unk>` spellings. A token set to
+    /// null is never guessed from its usual spelling.
+    pub fn from_config(tokenizer: &Tokenizer, config: &Value, eos_token_ids: &[u32]) -> Self {
+        let lookup = |field: &str, fallback: &str| match config.get(field) {
+            // Named as null: the model has no such token
+            Some(Value::Null) => None,
+            Some(value) => {
+                let configured = chat_template::special_token(value);
+                tokenizer
+                    .token_to_id(&configured)
+                    .filter(|_| !configured.is_empty())
+                    .or_else(|| tokenizer.token_to_id(fallback))
+            }
+            None => tokenizer.token_to_id(fallback),
+        };
+
+        SpecialTokens {
+            bos_token_id: lookup("bos_token", "<s>"),
+            eos_token_id: lookup("eos_token", "</s>").or_else(|| eos_token_ids.first().copied()),
+            pad_token_id: tokenizer
+                .get_padding()
+                .map(|padding| padding.pad_id)
+                .filter(|_| config["pad_token"].is_null())
+                .or_else(|| lookup("pad_token", "<pad>")),
+            unk_token_id: lookup("unk_token", "<unk>"),
+        }
+    }
+}
+
+/// A token added on top of the tokenizer's model vocabulary, e.g. a chat
+/// marker like `<|im_start|>`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AddedToken {
+    pub id: u32,
+    pub content: String,
+    /// Special tokens are left out when decoding with `skip_special_tokens`.
+    pub special: bool,
+}
+
+/// What `vocab_info_c` reports about a tokenizer.
+#[derive(Debug, Clone, Serialize)]
+pub struct VocabInfo {
+    /// Number of ids, added tokens included.
+    pub vocab_size: usize,
+    #[serde(flatten)]
+    pub special_tokens: SpecialTokens,
+    /// Sorted by id.
+    pub added_tokens: Vec<AddedToken>,
+}
+
+pub fn vocab_info(tokenizer: &Tokenizer, special_tokens: &SpecialTokens) -> VocabInfo {
+    let mut added_tokens: Vec<AddedToken> = tokenizer
+        .get_added_tokens_decoder()
+        .into_iter()
+        .map(|(id, token)| AddedToken { id, content: token.content, special: token.special })
+        .collect();
+    added_tokens.sort_unstable_by_key(|token| token.id);
+
+    VocabInfo {
+        vocab_size: tokenizer.get_vocab_size(true),
+        special_tokens: special_tokens.clone(),
+        added_tokens,
+    }
+}
+
+/// Id of `token` as it appears in the vocabulary, e.g. `▁hello`, not the
+/// text it decodes to.
+pub fn token_to_id(tokenizer: &Tokenizer, token: &str) -> Result<u32> {
+    tokenizer
+        .token_to_id(token)
+        .ok_or_else(|| LlmError::InvalidArgument(format!("Token {:?} is not in the vocabulary", token)))
+}
+
+/// The vocabulary entry for `id`.
+pub fn id_to_token(tokenizer: &Tokenizer, id: u32) -> Result<String> {
+    tokenizer.id_to_token(id).ok_or_else(|| {
+        LlmError::InvalidArgument(format!(
+            "Token id {} is outside the vocabulary ({})",
+            id,
+            tokenizer.get_vocab_size(true)
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::tests::word_level;
+    use serde_json::json;
+
+    #[test]
+    fn falls_back_to_the_usual_spellings() {
+        let tokenizer = word_level();
+        let special = SpecialTokens::resolve(&tokenizer, Path::new("missing/tokenizer_config.json"), &[]);
+        assert_eq!(
+            special,
+            SpecialTokens { bos_token_id: Some(1), eos_token_id: Some(2), pad_token_id: None, unk_token_id: Some(0) }
+        );
+    }
+
+    #[test]
+    fn reads_tokens_from_the_tokenizer_config() {
+        let tokenizer = word_level();
+        let config = json!({"bos_token": "</s>", "eos_token": {"content": "<s>"}, "pad_token": "<unk>", "unk_token": null});
+        let special = SpecialTokens::from_config(&tokenizer, &config, &[]);
+        assert_eq!(
+            special,
+            SpecialTokens { bos_token_id: Some(2), eos_token_id: Some(1), pad_token_id: Some(0), unk_token_id: None }
+        );
+    }
+
+    #[test]
+    fn null_tokens_do_not_fall_back() {
+        let tokenizer = word_level();
+        let config = json!({"bos_token": null, "eos_token": null, "pad_token": null});
+        let special = SpecialTokens::from_config(&tokenizer, &config, &[7]);
+        assert_eq!(
+            special,
+            SpecialTokens { bos_token_id: None, eos_token_id: Some(7), pad_token_id: None, unk_token_id: Some(0) }
+        );
+    }
+
+    #[test]
+    fn reports_size_and_added_tokens() {
+        let tokenizer = word_level();
+        let info = vocab_info(&tokenizer, &SpecialTokens::default());
+        assert_eq!(info.vocab_size, 9);
+        let added: Vec<_> = info.added_tokens.iter().map(|t| (t.id, t.content.as_str(), t.special)).collect();
+        assert_eq!(added, vec![(0, "<unk>", true), (1, "<s>", true), (2, "</s>", true)]);
+
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["bos_token_id"], Value::Null);
+        assert_eq!(json["added_tokens"][1]["content"], "<s>");
+    }
+
+    #[test]
+    fn looks_up_tokens_and_ids() {
+        let tokenizer = word_level();
+        assert_eq!(token_to_id(&tokenizer, "naïve").unwrap(), 8);
+        assert_eq!(id_to_token(&tokenizer, 7).unwrap(), "cat");
+        assert!(matches!(token_to_id(&tokenizer, "mars"), Err(LlmError::InvalidArgument(_))));
+        assert!(matches!(id_to_token(&tokenizer, 9), Err(LlmError::InvalidArgument(_))));
+    }
+}